@@ -0,0 +1,284 @@
+use crate::prime_check::{small_factor, RSAPublicKey};
+use num::traits::{One, Zero};
+use num::{BigUint, Integer};
+use std::fmt;
+
+/// Steps of Fermat's method before giving up. Primes drawn independently are about
+/// `2^(bit_size - 2)` apart and need far more; consecutive primes need one.
+const FERMAT_STEPS: u32 = 1 << 16;
+
+/// The primes whose residues of a ROCA modulus all lie in the subgroup generated by 65537.
+/// Keys from the affected library are built from primes `k * M + (65537^a mod M)`, where `M` is
+/// the product of at least the first 39 primes.
+const ROCA_PRIMES: [u32; 38] = [
+    3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167,
+];
+
+/// A weakness `audit` found in a public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Weakness {
+    /// `n` is 0 or 1, so it is not a modulus at all and no other check applies.
+    MalformedModulus,
+    /// `n` is divisible by a prime from `SMALL_PRIMES`.
+    SmallFactor,
+    /// The factors are close enough for Fermat's method.
+    CloseFactors,
+    /// `d` is small enough for Wiener's continued-fraction attack, which recovered it.
+    SmallPrivateExponent(BigUint),
+    /// `n` carries the fingerprint of keys affected by ROCA (CVE-2017-15361).
+    Roca,
+}
+
+impl fmt::Display for Weakness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Weakness::MalformedModulus => write!(f, "the modulus is not a product of primes"),
+            Weakness::SmallFactor => write!(f, "the modulus has a small prime factor"),
+            Weakness::CloseFactors => {
+                write!(f, "the factors are close together (Fermat factorisation)")
+            }
+            Weakness::SmallPrivateExponent(d) => {
+                write!(
+                    f,
+                    "the private exponent is small (Wiener's attack): d = {}",
+                    d
+                )
+            }
+            Weakness::Roca => write!(f, "the modulus has the ROCA fingerprint"),
+        }
+    }
+}
+
+/// One weakness and, where the attack yields them, the factors of `n` in ascending order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub weakness: Weakness,
+    pub factors: Vec<BigUint>,
+}
+
+impl Finding {
+    fn new(weakness: Weakness, p: BigUint, q: BigUint) -> Self {
+        Self {
+            weakness,
+            factors: if p < q { vec![p, q] } else { vec![q, p] },
+        }
+    }
+}
+
+/// Runs every cheap attack against `key`. An empty report only means none of them worked.
+pub fn audit(key: &RSAPublicKey) -> Vec<Finding> {
+    let (n, e) = key;
+    if *n <= BigUint::one() {
+        return vec![Finding {
+            weakness: Weakness::MalformedModulus,
+            factors: vec![],
+        }];
+    }
+    let mut findings = vec![];
+    if let Some(prime) = small_factor(n).filter(|&prime| *n != BigUint::from(prime)) {
+        findings.push(Finding::new(
+            Weakness::SmallFactor,
+            BigUint::from(prime),
+            n / prime,
+        ));
+    }
+    if let Some((p, q)) = fermat_factor(n, FERMAT_STEPS) {
+        findings.push(Finding::new(Weakness::CloseFactors, p, q));
+    }
+    if let Some((d, p, q)) = wiener(n, e) {
+        findings.push(Finding::new(Weakness::SmallPrivateExponent(d), p, q));
+    }
+    // The affected library made no keys below 512 bits, and tiny moduli pass trivially.
+    if n.bits() >= 512 && roca_fingerprint(n) {
+        findings.push(Finding {
+            weakness: Weakness::Roca,
+            factors: vec![],
+        });
+    }
+    findings
+}
+
+/// Walks `a` up from `ceil(sqrt(n))` looking for `a^2 - n = b^2`, so that `n = (a + b)(a - b)`.
+pub fn fermat_factor(n: &BigUint, steps: u32) -> Option<(BigUint, BigUint)> {
+    if n.is_even() || n.is_zero() {
+        return None;
+    }
+    let mut a = n.sqrt();
+    if &a * &a < *n {
+        a += 1_u32;
+    }
+    let mut b_squared = &a * &a - n;
+    for _ in 0..steps {
+        // Squares are 0, 1, 4, 9, 16, 17, 25, 33, 36, 41, 49 or 57 modulo 64.
+        let low = b_squared.iter_u64_digits().next().unwrap_or(0) & 63;
+        if 0x0202_0212_0203_0213_u64 >> low & 1 == 1 {
+            let b = b_squared.sqrt();
+            if &b * &b == b_squared {
+                let q = &a - &b;
+                return (!q.is_one()).then(|| (&a + b, q));
+            }
+        }
+        b_squared += (&a << 1) + 1_u32;
+        a += 1_u32;
+    }
+    None
+}
+
+/// Wiener's attack: when `d < n^(1/4) / 3`, `k / d` is a convergent of the continued fraction
+/// of `e / n`, and each candidate `d` gives `phi` and with it a quadratic for `p` and `q`.
+/// Returns `(d, p, q)`.
+pub fn wiener(n: &BigUint, e: &BigUint) -> Option<(BigUint, BigUint, BigUint)> {
+    let (mut numerator, mut denominator) = (e.clone(), n.clone());
+    let (mut k_before, mut k) = (BigUint::zero(), BigUint::one());
+    let (mut d_before, mut d) = (BigUint::one(), BigUint::zero());
+    while !denominator.is_zero() {
+        let (quotient, remainder) = numerator.div_rem(&denominator);
+        (numerator, denominator) = (denominator, remainder);
+        (k_before, k) = (k.clone(), &quotient * &k + k_before);
+        (d_before, d) = (d.clone(), &quotient * &d + d_before);
+        if k.is_zero() {
+            continue;
+        }
+        let (phi, rest) = (e * &d - 1_u32).div_rem(&k);
+        if !rest.is_zero() || phi > *n {
+            continue;
+        }
+        // p + q = n - phi + 1, and p, q are the roots of x^2 - (p + q)x + n.
+        let sum = n - phi + 1_u32;
+        let sum_squared = &sum * &sum;
+        let four_n = n << 2;
+        if sum_squared < four_n {
+            continue;
+        }
+        let discriminant = sum_squared - four_n;
+        let root = discriminant.sqrt();
+        if &root * &root != discriminant || (&sum + &root).is_odd() {
+            continue;
+        }
+        let (p, q): (BigUint, BigUint) = ((&sum + &root) >> 1, (&sum - &root) >> 1);
+        if &p * &q == *n && !q.is_one() {
+            return Some((d, p, q));
+        }
+    }
+    None
+}
+
+/// The ROCA fingerprint test of Nemec et al.: `n mod r` lies in the subgroup generated by
+/// 65537 for every `r` in `ROCA_PRIMES`. A random modulus passes with probability about 2^-150.
+pub fn roca_fingerprint(n: &BigUint) -> bool {
+    ROCA_PRIMES.iter().all(|&prime| {
+        let residue = (n % prime).iter_u32_digits().next().unwrap_or(0);
+        let generator = 65537 % prime;
+        let mut power = 1;
+        loop {
+            if power == residue {
+                break true;
+            }
+            power = power * generator % prime;
+            if power == 1 {
+                break false;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prime_check::{is_prime, mod_inverse, PrimalityTest, PrimeUtils, DEFAULT_EXPONENT};
+    use num::bigint::RandBigInt;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn next_prime(mut candidate: BigUint) -> BigUint {
+        candidate |= BigUint::one();
+        while !is_prime(&candidate, PrimalityTest::BailliePsw) {
+            candidate += 2_u32;
+        }
+        candidate
+    }
+
+    #[test]
+    fn sound_keys_pass() {
+        let mut utils = PrimeUtils::with_rng(768, ChaCha20Rng::seed_from_u64(18));
+        let (public_key, _) = utils.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+        assert_eq!(audit(&public_key), vec![]);
+    }
+
+    #[test]
+    fn consecutive_primes_fall_to_fermat() {
+        let mut rng = ChaCha20Rng::seed_from_u64(19);
+        let p = next_prime(rng.gen_biguint(512));
+        let q = next_prime(&p + 2_u32);
+        let findings = audit(&(&p * &q, BigUint::from(DEFAULT_EXPONENT)));
+        assert_eq!(findings, vec![Finding::new(Weakness::CloseFactors, p, q)]);
+    }
+
+    #[test]
+    fn small_private_exponents_fall_to_wiener() {
+        let mut rng = ChaCha20Rng::seed_from_u64(20);
+        let p = next_prime(rng.gen_biguint(256) | BigUint::one() << 255_u32);
+        let q = next_prime(rng.gen_biguint(256) | BigUint::one() << 255_u32);
+        let phi = (&p - 1_u32) * (&q - 1_u32);
+        let d = loop {
+            // Well below n^(1/4) / 3 for a 512-bit n.
+            let d = rng.gen_biguint(120) | BigUint::one();
+            if d.gcd(&phi).is_one() {
+                break d;
+            }
+        };
+        let e = mod_inverse(&d, &phi).unwrap();
+        let findings = audit(&(&p * &q, e));
+        assert_eq!(
+            findings,
+            vec![Finding::new(Weakness::SmallPrivateExponent(d), p, q)]
+        );
+    }
+
+    #[test]
+    fn roca_moduli_are_fingerprinted() {
+        let mut rng = ChaCha20Rng::seed_from_u64(21);
+        let m: BigUint = ROCA_PRIMES.iter().product::<BigUint>() * 2_u32;
+        let mut roca_prime = || loop {
+            let a = rng.gen_biguint(64);
+            let k = rng.gen_biguint(256);
+            let candidate = k * &m + BigUint::from(65537_u32).modpow(&a, &m);
+            if is_prime(&candidate, PrimalityTest::BailliePsw) {
+                break candidate;
+            }
+        };
+        let n = roca_prime() * roca_prime();
+        assert!(roca_fingerprint(&n));
+        let findings = audit(&(n, BigUint::from(DEFAULT_EXPONENT)));
+        assert_eq!(findings[0].weakness, Weakness::Roca);
+        assert!(!roca_fingerprint(
+            &(BigUint::from(65537_u32) * 3_u32 + 2_u32)
+        ));
+    }
+
+    #[test]
+    fn small_factors_are_split_off() {
+        let mut rng = ChaCha20Rng::seed_from_u64(22);
+        let p = next_prime(rng.gen_biguint(512));
+        let findings = audit(&(&p * 17863_u32, BigUint::from(DEFAULT_EXPONENT)));
+        assert_eq!(
+            findings[0],
+            Finding::new(Weakness::SmallFactor, BigUint::from(17863_u32), p)
+        );
+    }
+
+    #[test]
+    fn moduli_below_two_are_malformed() {
+        for n in [0_u32, 1] {
+            let findings = audit(&(BigUint::from(n), BigUint::from(DEFAULT_EXPONENT)));
+            assert_eq!(
+                findings,
+                vec![Finding {
+                    weakness: Weakness::MalformedModulus,
+                    factors: vec![],
+                }]
+            );
+        }
+    }
+}
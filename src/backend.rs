@@ -0,0 +1,184 @@
+use crate::montgomery::MontgomeryContext;
+use crate::prime_check::mod_inverse;
+use num::traits::Zero;
+use num::{BigUint, Integer};
+use std::fmt;
+
+/// The arithmetic the private-key operations need, so that they can run on either `VarTime`
+/// or `ConstantTime`, whose running time depends only on the widths of its inputs.
+///
+/// Private-key operations use `ConstantTime`. `VarTime` is a reference implementation on
+/// `BigUint` that the tests run the same operations on to cross-check it; key generation and
+/// public-key operations work on `BigUint` and `MontgomeryContext` directly.
+pub trait Backend {
+    type Uint: Clone + PartialEq + fmt::Debug;
+    /// A modulus with everything that depends only on it precomputed.
+    type Modulus: fmt::Debug;
+
+    /// Reads a big-endian number. The width of the result depends only on `bytes.len()`.
+    fn from_bytes_be(bytes: &[u8]) -> Self::Uint;
+    /// Writes `value` big-endian in exactly `len` bytes, or `None` if it does not fit, as
+    /// I2OSP in RFC 8017.
+    fn to_bytes_be(value: &Self::Uint, len: usize) -> Option<Vec<u8>>;
+
+    fn add(a: &Self::Uint, b: &Self::Uint) -> Self::Uint;
+    fn mul(a: &Self::Uint, b: &Self::Uint) -> Self::Uint;
+    /// `a mod modulus` for a nonzero `modulus`.
+    fn rem(a: &Self::Uint, modulus: &Self::Uint) -> Self::Uint;
+    /// `(a - b) mod modulus` for `a, b < modulus`.
+    fn sub_mod(a: &Self::Uint, b: &Self::Uint, modulus: &Self::Uint) -> Self::Uint;
+
+    /// `None` for an even modulus or one below 3.
+    fn modulus(modulus: &Self::Uint) -> Option<Self::Modulus>;
+    fn modpow(base: &Self::Uint, exponent: &Self::Uint, modulus: &Self::Modulus) -> Self::Uint;
+    /// `a^-1 mod modulus` for an odd `modulus`, or `None` when `a` is not invertible.
+    fn modinv(a: &Self::Uint, modulus: &Self::Uint) -> Option<Self::Uint>;
+    /// `gcd(a, b)` for an odd `b`.
+    fn gcd(a: &Self::Uint, b: &Self::Uint) -> Self::Uint;
+}
+
+/// `BigUint`, with exponentiation through `MontgomeryContext`. Only used to cross-check
+/// `ConstantTime` in tests.
+pub struct VarTime;
+
+impl Backend for VarTime {
+    type Uint = BigUint;
+    type Modulus = MontgomeryContext;
+
+    fn from_bytes_be(bytes: &[u8]) -> BigUint {
+        BigUint::from_bytes_be(bytes)
+    }
+
+    fn to_bytes_be(value: &BigUint, len: usize) -> Option<Vec<u8>> {
+        if value.bits() > 8 * len as u64 {
+            return None;
+        }
+        let mut bytes = vec![0; len];
+        if !value.is_zero() {
+            let digits = value.to_bytes_be();
+            bytes[len - digits.len()..].copy_from_slice(&digits);
+        }
+        Some(bytes)
+    }
+
+    fn add(a: &BigUint, b: &BigUint) -> BigUint {
+        a + b
+    }
+
+    fn mul(a: &BigUint, b: &BigUint) -> BigUint {
+        a * b
+    }
+
+    fn rem(a: &BigUint, modulus: &BigUint) -> BigUint {
+        a % modulus
+    }
+
+    fn sub_mod(a: &BigUint, b: &BigUint, modulus: &BigUint) -> BigUint {
+        (modulus + a - b) % modulus
+    }
+
+    fn modulus(modulus: &BigUint) -> Option<MontgomeryContext> {
+        MontgomeryContext::new(modulus)
+    }
+
+    fn modpow(base: &BigUint, exponent: &BigUint, modulus: &MontgomeryContext) -> BigUint {
+        modulus.modpow(base, exponent)
+    }
+
+    fn modinv(a: &BigUint, modulus: &BigUint) -> Option<BigUint> {
+        mod_inverse(a, modulus)
+    }
+
+    fn gcd(a: &BigUint, b: &BigUint) -> BigUint {
+        a.gcd(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constant_time::ConstantTime;
+    use num::bigint::RandBigInt;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    /// Runs `B` on `operands` and writes every result in `len` bytes.
+    fn results<B: Backend>(operands: [&BigUint; 5], len: usize) -> Vec<Option<Vec<u8>>> {
+        let [a, b, wide, exponent, modulus] =
+            operands.map(|value| B::from_bytes_be(&value.to_bytes_be()));
+        let (a, b) = (B::rem(&a, &modulus), B::rem(&b, &modulus));
+        let context = B::modulus(&modulus).unwrap();
+        let bytes = |value: &B::Uint| B::to_bytes_be(value, 2 * len + 1);
+        vec![
+            bytes(&a),
+            bytes(&B::add(&a, &b)),
+            bytes(&B::mul(&a, &b)),
+            bytes(&B::rem(&wide, &modulus)),
+            bytes(&B::sub_mod(&a, &b, &modulus)),
+            bytes(&B::sub_mod(&b, &a, &modulus)),
+            bytes(&B::modpow(&wide, &exponent, &context)),
+            B::modinv(&a, &modulus).and_then(|inverse| bytes(&inverse)),
+            bytes(&B::gcd(&a, &modulus)),
+            bytes(&B::gcd(&wide, &modulus)),
+        ]
+    }
+
+    #[test]
+    fn backends_agree() {
+        let mut rng = ChaCha20Rng::seed_from_u64(33);
+        for bits in [2, 63, 64, 65, 127, 512, 1000, 2048] {
+            let len = (bits as usize).div_ceil(8);
+            for _ in 0..4 {
+                let modulus = rng.gen_biguint(bits)
+                    | BigUint::from(1_u32) << (bits - 1)
+                    | BigUint::from(3_u32);
+                let a = rng.gen_biguint_below(&modulus);
+                let b = rng.gen_biguint_below(&modulus);
+                let wide = rng.gen_biguint(2 * bits);
+                let exponent = rng.gen_biguint(bits + 64);
+                let operands = [&a, &b, &wide, &exponent, &modulus];
+                assert_eq!(
+                    results::<VarTime>(operands, len),
+                    results::<ConstantTime>(operands, len),
+                    "{}-bit modulus",
+                    bits
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_edge_cases() {
+        let three = BigUint::from(3_u32);
+        let modulus = BigUint::from(3_u32 * 5 * 7 * 11 * 13);
+        let wide = BigUint::from(u64::MAX) * 42_u32;
+        let zero = BigUint::zero();
+        // Shared factors, zero, and a zero exponent.
+        for operands in [
+            [&three, &zero, &wide, &zero, &modulus],
+            [
+                &BigUint::from(65_u32),
+                &(&modulus - 1_u32),
+                &modulus,
+                &three,
+                &modulus,
+            ],
+            [&zero, &zero, &zero, &wide, &three],
+        ] {
+            assert_eq!(
+                results::<VarTime>(operands, 9),
+                results::<ConstantTime>(operands, 9)
+            );
+        }
+        assert_eq!(VarTime::modinv(&three, &modulus), None);
+        assert_eq!(VarTime::to_bytes_be(&modulus, 1), None);
+        assert_eq!(
+            ConstantTime::to_bytes_be(&ConstantTime::from_bytes_be(&[1, 0]), 1),
+            None
+        );
+        assert_eq!(
+            VarTime::to_bytes_be(&zero, 2),
+            ConstantTime::to_bytes_be(&ConstantTime::from_bytes_be(&[]), 2)
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use num::traits::One;
+use num::{BigUint, Integer};
+
+/// Two moduli, by index into the input, with a factor in common. When the moduli are equal,
+/// `factor` is the whole modulus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedFactor {
+    pub first: usize,
+    pub second: usize,
+    pub factor: BigUint,
+}
+
+/// Levels of the product tree, leaves first: every node is the product of its two children,
+/// and an odd node out is carried up unchanged.
+fn product_tree(moduli: &[BigUint]) -> Vec<Vec<BigUint>> {
+    let mut levels = vec![moduli.to_vec()];
+    while levels.last().unwrap().len() > 1 {
+        let level = levels.last().unwrap();
+        let next = level.chunks(2).map(|pair| pair.iter().product()).collect();
+        levels.push(next);
+    }
+    levels
+}
+
+/// Heninger et al.'s batch GCD: `gcd(n_i, product of all other n_j)` for every modulus, in
+/// quasilinear time. The remainder tree reduces the full product modulo `node^2` on the way
+/// down, so each leaf ends up with `P mod n_i^2` and `(P mod n_i^2) / n_i` is the product of
+/// the others modulo `n_i`. A zero modulus would zero the product, so moduli below 2, which
+/// share no factor with anything, are left out of the trees and get 1.
+pub fn batch_gcd(moduli: &[BigUint]) -> Vec<BigUint> {
+    if moduli.is_empty() {
+        return vec![];
+    }
+    let one = BigUint::one();
+    if moduli.iter().any(|modulus| *modulus <= one) {
+        let kept: Vec<BigUint> = moduli
+            .iter()
+            .filter(|&modulus| *modulus > one)
+            .cloned()
+            .collect();
+        let mut divisors = batch_gcd(&kept).into_iter();
+        return moduli
+            .iter()
+            .map(|modulus| {
+                if *modulus > one {
+                    divisors.next().unwrap()
+                } else {
+                    BigUint::one()
+                }
+            })
+            .collect();
+    }
+    let mut levels = product_tree(moduli);
+    let mut remainders = levels.pop().unwrap();
+    while let Some(level) = levels.pop() {
+        remainders = level
+            .iter()
+            .enumerate()
+            .map(|(i, node)| &remainders[i / 2] % (node * node))
+            .collect();
+    }
+    remainders
+        .iter()
+        .zip(moduli)
+        .map(|(remainder, modulus)| (remainder / modulus).gcd(modulus))
+        .collect()
+}
+
+/// Every pair of moduli that share a factor. Only the moduli `batch_gcd` flags are compared
+/// pairwise, and on a healthy corpus there are none.
+pub fn shared_factors(moduli: &[BigUint]) -> Vec<SharedFactor> {
+    let flagged: Vec<usize> = batch_gcd(moduli)
+        .iter()
+        .enumerate()
+        .filter(|(_, divisor)| !divisor.is_one())
+        .map(|(i, _)| i)
+        .collect();
+    let mut pairs = vec![];
+    for (k, &first) in flagged.iter().enumerate() {
+        for &second in &flagged[k + 1..] {
+            let factor = moduli[first].gcd(&moduli[second]);
+            if !factor.is_one() {
+                pairs.push(SharedFactor {
+                    first,
+                    second,
+                    factor,
+                });
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prime_check::PrimeUtils;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn batch_gcd_matches_pairwise_gcd() {
+        let mut utils = PrimeUtils::with_rng(256, ChaCha20Rng::seed_from_u64(23));
+        let primes: Vec<BigUint> = (0..12).map(|_| utils.gen_prime(128)).collect();
+        // Keys 0 and 3 share a prime, as do 1 and 6, and 2 and 5 are the same key.
+        let pairs = [
+            (0, 1),
+            (2, 3),
+            (4, 5),
+            (0, 6),
+            (7, 8),
+            (4, 5),
+            (3, 9),
+            (10, 11),
+        ];
+        let moduli: Vec<BigUint> = pairs
+            .iter()
+            .map(|&(a, b)| &primes[a] * &primes[b])
+            .collect();
+
+        let expected: Vec<BigUint> = (0..moduli.len())
+            .map(|i| {
+                let others: BigUint = (0..moduli.len())
+                    .filter(|&j| j != i)
+                    .map(|j| &moduli[j])
+                    .product();
+                moduli[i].gcd(&others)
+            })
+            .collect();
+        assert_eq!(batch_gcd(&moduli), expected);
+
+        let found: Vec<(usize, usize)> = shared_factors(&moduli)
+            .iter()
+            .map(|shared| (shared.first, shared.second))
+            .collect();
+        assert_eq!(found, vec![(0, 3), (1, 6), (2, 5)]);
+        assert_eq!(shared_factors(&moduli)[2].factor, moduli[2]);
+        assert_eq!(batch_gcd(&moduli[..1]), vec![BigUint::one()]);
+
+        let degenerate = [
+            BigUint::from(0_u32),
+            moduli[0].clone(),
+            BigUint::one(),
+            moduli[3].clone(),
+        ];
+        assert_eq!(
+            batch_gcd(&degenerate),
+            vec![
+                BigUint::one(),
+                primes[0].clone(),
+                BigUint::one(),
+                primes[0].clone()
+            ]
+        );
+    }
+}
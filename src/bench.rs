@@ -1,85 +1,46 @@
-#[cfg(test)]
-pub mod autobench {
-    use crate::convert::{base64_to_key, key_to_base64};
-    use crate::prime_check::{decrypt, encrypt, PrimeUtils};
-    use rand::Rng;
-    use test::bench::Bencher;
-
-    #[bench]
-    pub fn test_gen_key(b: &mut Bencher) {
-        let mut checker = PrimeUtils::new(1024);
-        b.iter(|| {
-            let (pub_key, pri_key) = checker.gen_key();
-            println!("public key is {}", key_to_base64(&pub_key));
-            println!("private key is {}", key_to_base64(&pri_key));
-        })
-    }
-
-    #[bench]
-    pub fn test_encrypt(b: &mut Bencher) {
-        let public_key =
-            base64_to_key(&String::from_utf8(std::fs::read("id_rsa.pub").unwrap()).unwrap());
-        let mut rng = rand::thread_rng();
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-            abcdefghijklmnopqrstuvwxyz\
-            0123456789)(*&^%$#@!~";
-        const LENGTH: u64 = 1000_00;
-        let sequence: String = (0..LENGTH)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect();
-        b.iter(|| {
-            encrypt(&public_key, &sequence);
-        })
-    }
-
-    #[bench]
-    pub fn test_decrypt(b: &mut Bencher) {
-        let public_key =
-            base64_to_key(&String::from_utf8(std::fs::read("id_rsa.pub").unwrap()).unwrap());
-        let private_key =
-            base64_to_key(&String::from_utf8(std::fs::read("id_rsa").unwrap()).unwrap());
-        let mut rng = rand::thread_rng();
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-            abcdefghijklmnopqrstuvwxyz\
-            0123456789)(*&^%$#@!~";
-        const LENGTH: u64 = 1000_00;
-        let sequence: String = (0..LENGTH)
-            .map(|_| {
-                let idx = rng.gen_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect();
-        let secret = encrypt(&public_key, &sequence);
-        b.iter(|| {
-            decrypt(&private_key, &secret);
-        })
-    }
-}
-
-use crate::convert::{base64_to_key, key_to_base64};
-use crate::prime_check::{decrypt, encrypt, PrimeUtils};
+use crate::convert::{base64_to_key, base64_to_private_key, key_to_base64, private_key_to_base64};
+use crate::montgomery::MontgomeryContext;
+use crate::prime_check::{decrypt, encrypt, PrimeUtils, DEFAULT_EXPONENT};
+use num::bigint::RandBigInt;
+use num::BigUint;
 use rand::Rng;
 use std::time::Instant;
 
 pub fn bench_gen_key() {
     println!("----- Begin bench_gen_key -----");
-    let mut checker = PrimeUtils::new(1024);
+    let mut checker = PrimeUtils::new(2048);
     let start = Instant::now();
-    let (pub_key, pri_key) = checker.gen_key();
+    let (pub_key, pri_key) = checker.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
     println!("public key is {}", key_to_base64(&pub_key));
-    println!("private key is {}", key_to_base64(&pri_key));
+    println!("private key is {}", private_key_to_base64(&pri_key));
     let duration = start.elapsed();
     println!("Time elapsed: {}ms", duration.as_millis());
     println!("------ End bench_gen_key ------\n");
 }
 
+pub fn bench_sieve() {
+    println!("----- Begin bench_sieve -----");
+    const KEYS: u32 = 8;
+    for sieve in [false, true] {
+        let mut checker = PrimeUtils::new(2048).with_sieve(sieve);
+        let start = Instant::now();
+        for _ in 0..KEYS {
+            checker.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+        }
+        let duration = start.elapsed();
+        println!(
+            "{}: {}ms per key",
+            if sieve { "Sieve" } else { "Trial division" },
+            duration.as_millis() / u128::from(KEYS)
+        );
+    }
+    println!("------ End bench_sieve ------\n");
+}
+
 pub fn bench_encrypt() {
     println!("----- Begin bench_encrypt -----");
     let public_key =
-        base64_to_key(&String::from_utf8(std::fs::read("id_rsa.pub").unwrap()).unwrap());
+        base64_to_key(&String::from_utf8(std::fs::read("id_rsa.pub").unwrap()).unwrap()).unwrap();
     let mut rng = rand::thread_rng();
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
         abcdefghijklmnopqrstuvwxyz\
@@ -92,7 +53,7 @@ pub fn bench_encrypt() {
         })
         .collect();
     let start = Instant::now();
-    encrypt(&public_key, &sequence);
+    encrypt(&public_key, &sequence).unwrap();
     let duration = start.elapsed();
     println!("Time elapsed: {}ms", duration.as_millis());
     println!("------ End bench_encrypt ------\n");
@@ -101,8 +62,10 @@ pub fn bench_encrypt() {
 pub fn bench_decrypt() {
     println!("----- Begin bench_decrypt -----");
     let public_key =
-        base64_to_key(&String::from_utf8(std::fs::read("id_rsa.pub").unwrap()).unwrap());
-    let private_key = base64_to_key(&String::from_utf8(std::fs::read("id_rsa").unwrap()).unwrap());
+        base64_to_key(&String::from_utf8(std::fs::read("id_rsa.pub").unwrap()).unwrap()).unwrap();
+    let private_key =
+        base64_to_private_key(&String::from_utf8(std::fs::read("id_rsa").unwrap()).unwrap())
+            .unwrap();
     let mut rng = rand::thread_rng();
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
         abcdefghijklmnopqrstuvwxyz\
@@ -114,10 +77,105 @@ pub fn bench_decrypt() {
             CHARSET[idx] as char
         })
         .collect();
-    let secret = encrypt(&public_key, &sequence);
+    let secret = encrypt(&public_key, &sequence).unwrap();
     let start = Instant::now();
-    decrypt(&private_key, &secret);
+    decrypt(&private_key, &secret).unwrap();
     let duration = start.elapsed();
     println!("Time elapsed: {}ms", duration.as_millis());
     println!("------ End bench_decrypt ------\n");
 }
+
+pub fn bench_montgomery() {
+    println!("----- Begin bench_montgomery -----");
+    const ROUNDS: u32 = 20;
+    let mut rng = rand::thread_rng();
+    for bits in [2048, 4096] {
+        // A private-key exponentiation: odd modulus, full-size base and exponent.
+        let n = rng.gen_biguint(bits) | BigUint::from(1_u32) << (bits - 1) | BigUint::from(1_u32);
+        let bases: Vec<BigUint> = (0..ROUNDS).map(|_| rng.gen_biguint_below(&n)).collect();
+        let exponent = rng.gen_biguint(bits);
+
+        let start = Instant::now();
+        for base in &bases {
+            base.modpow(&exponent, &n);
+        }
+        let biguint = start.elapsed();
+
+        let start = Instant::now();
+        let context = MontgomeryContext::new(&n).unwrap();
+        for base in &bases {
+            context.modpow(base, &exponent);
+        }
+        let montgomery = start.elapsed();
+
+        println!(
+            "{} bits: BigUint::modpow {}us, Montgomery {}us per exponentiation",
+            bits,
+            biguint.as_micros() / u128::from(ROUNDS),
+            montgomery.as_micros() / u128::from(ROUNDS)
+        );
+    }
+    println!("------ End bench_montgomery ------\n");
+}
+
+#[cfg(test)]
+pub mod autobench {
+    use crate::convert::{key_to_base64, private_key_to_base64};
+    use crate::prime_check::{decrypt, encrypt, PrimeUtils, DEFAULT_EXPONENT};
+    use num::BigUint;
+    use rand::Rng;
+    use test::bench::Bencher;
+
+    #[bench]
+    pub fn test_gen_key(b: &mut Bencher) {
+        let mut checker = PrimeUtils::new(2048);
+        b.iter(|| {
+            let (pub_key, pri_key) = checker.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+            println!("public key is {}", key_to_base64(&pub_key));
+            println!("private key is {}", private_key_to_base64(&pri_key));
+        })
+    }
+
+    #[bench]
+    pub fn test_encrypt(b: &mut Bencher) {
+        let (public_key, _) = PrimeUtils::new(2048)
+            .gen_key(&BigUint::from(DEFAULT_EXPONENT))
+            .unwrap();
+        let mut rng = rand::thread_rng();
+        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+            abcdefghijklmnopqrstuvwxyz\
+            0123456789)(*&^%$#@!~";
+        const LENGTH: u64 = 100_000;
+        let sequence: String = (0..LENGTH)
+            .map(|_| {
+                let idx = rng.gen_range(0..CHARSET.len());
+                CHARSET[idx] as char
+            })
+            .collect();
+        b.iter(|| {
+            encrypt(&public_key, &sequence).unwrap();
+        })
+    }
+
+    #[bench]
+    pub fn test_decrypt(b: &mut Bencher) {
+        let (public_key, private_key) = PrimeUtils::new(2048)
+            .gen_key(&BigUint::from(DEFAULT_EXPONENT))
+            .unwrap();
+        let mut rng = rand::thread_rng();
+        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+            abcdefghijklmnopqrstuvwxyz\
+            0123456789)(*&^%$#@!~";
+        const LENGTH: u64 = 100_000;
+        let sequence: String = (0..LENGTH)
+            .map(|_| {
+                let idx = rng.gen_range(0..CHARSET.len());
+                CHARSET[idx] as char
+            })
+            .collect();
+        let secret = encrypt(&public_key, &sequence).unwrap();
+        b.iter(|| {
+            decrypt(&private_key, &secret).unwrap();
+        })
+    }
+}
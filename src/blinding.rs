@@ -0,0 +1,141 @@
+use crate::prime_check::mod_inverse;
+use num::bigint::RandBigInt;
+use num::traits::One;
+use num::BigUint;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Bits of the random multiplier `k` in exponent blinding.
+pub const EXPONENT_MASK_SIZE: u64 = 64;
+
+/// Per-key blinding state for private-key operations. The input is multiplied by `r^e` before
+/// the exponentiation and the result by `r^-1` after it, so the timing of the exponentiation
+/// depends on a value the attacker does not know. Both factors are squared after every use
+/// instead of drawing a fresh `r`, which costs two multiplications instead of an inversion.
+///
+/// The state is not part of the key's value: clones start over with their own `r`, and it is
+/// ignored by `==`.
+pub struct Blinding {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Seeded from the OS on first use unless one was injected.
+    rng: Option<Box<dyn RngCore + Send>>,
+    /// `(r^e mod n, r^-1 mod n)` for the next operation, after the `(n, e)` they were drawn for.
+    factors: Option<((BigUint, BigUint), (BigUint, BigUint))>,
+    exponent: bool,
+}
+
+impl Blinding {
+    pub fn with_rng<R: RngCore + Send + 'static>(rng: R) -> Self {
+        let blinding = Self::default();
+        blinding.state.lock().unwrap().rng = Some(Box::new(rng));
+        blinding
+    }
+
+    /// Also adds a random multiple of the group order to the exponent of every operation.
+    pub fn set_exponent_blinding(&mut self, enabled: bool) {
+        self.state.get_mut().unwrap().exponent = enabled;
+    }
+
+    pub fn exponent_blinding(&self) -> bool {
+        self.state.lock().unwrap().exponent
+    }
+
+    /// The factors for one operation under `(n, e)`, drawing `r` on first use, or when the key
+    /// has changed since, and squaring the cached pair for the next.
+    pub fn next_factors(&self, n: &BigUint, e: &BigUint) -> (BigUint, BigUint) {
+        let mut state = self.state.lock().unwrap();
+        let factors = match state.factors.take() {
+            Some(((cached_n, cached_e), factors)) if cached_n == *n && cached_e == *e => factors,
+            _ => {
+                let rng = state.rng();
+                loop {
+                    let r = rng.gen_biguint_range(&BigUint::one(), n);
+                    if let Some(r_inverse) = mod_inverse(&r, n) {
+                        break (r.modpow(e, n), r_inverse);
+                    }
+                }
+            }
+        };
+        let (blind, unblind) = &factors;
+        state.factors = Some((
+            (n.clone(), e.clone()),
+            (blind * blind % n, unblind * unblind % n),
+        ));
+        factors
+    }
+
+    /// `exponent + k * order` for a fresh random `k` when exponent blinding is on, where
+    /// `order` is a multiple of the order of the group the exponentiation runs in.
+    pub fn mask_exponent(&self, exponent: &BigUint, order: &BigUint) -> BigUint {
+        let mut state = self.state.lock().unwrap();
+        if !state.exponent {
+            return exponent.clone();
+        }
+        let k = state.rng().gen_biguint(EXPONENT_MASK_SIZE);
+        exponent + k * order
+    }
+
+    /// The cached pair, for tests that check the refresh.
+    #[cfg(test)]
+    pub fn cached_factors(&self) -> Option<(BigUint, BigUint)> {
+        let state = self.state.lock().unwrap();
+        state.factors.as_ref().map(|(_, factors)| factors.clone())
+    }
+}
+
+impl State {
+    fn rng(&mut self) -> &mut (dyn RngCore + Send) {
+        self.rng
+            .get_or_insert_with(|| Box::new(ChaCha20Rng::from_entropy()))
+            .as_mut()
+    }
+}
+
+impl Default for Blinding {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State {
+                rng: None,
+                factors: None,
+                exponent: false,
+            }),
+        }
+    }
+}
+
+impl Clone for Blinding {
+    /// A clone of an injected generator is seeded from it, so tests stay deterministic.
+    fn clone(&self) -> Self {
+        let mut state = self.state.lock().unwrap();
+        let rng = state
+            .rng
+            .as_mut()
+            .map(|rng| Box::new(ChaCha20Rng::from_rng(rng).unwrap()) as Box<dyn RngCore + Send>);
+        Self {
+            state: Mutex::new(State {
+                rng,
+                factors: None,
+                exponent: state.exponent,
+            }),
+        }
+    }
+}
+
+impl PartialEq for Blinding {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Blinding {}
+
+impl fmt::Debug for Blinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blinding").finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,181 @@
+use crate::prime_check::trial_division;
+use num::bigint::RandBigInt;
+use num::traits::{One, Zero};
+use num::{BigUint, Integer};
+use rand::Rng;
+
+/// Primes of at most this many bits are proven by trial division alone.
+const SEED_SIZE: u64 = 32;
+
+/// One link of a certificate chain. The prime of the previous link, `q`, divides `prime - 1`
+/// and exceeds `sqrt(prime)`, and `witness` satisfies Pocklington's criterion for it:
+/// `witness^(prime - 1) = 1` and `gcd(witness^((prime - 1) / q) - 1, prime) = 1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PocklingtonStep {
+    pub prime: BigUint,
+    pub witness: BigUint,
+}
+
+/// A proof that `prime()` is prime: a seed small enough to check by trial division, followed by
+/// Pocklington steps that each build on the prime before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrimeCertificate {
+    pub seed: u64,
+    pub steps: Vec<PocklingtonStep>,
+}
+
+impl PrimeCertificate {
+    /// The prime this certificate proves.
+    pub fn prime(&self) -> BigUint {
+        self.steps
+            .last()
+            .map_or_else(|| BigUint::from(self.seed), |step| step.prime.clone())
+    }
+
+    /// Checks the whole chain without trusting how it was produced.
+    pub fn verify(&self) -> bool {
+        if self.seed >> SEED_SIZE != 0 || !small_prime(self.seed) {
+            return false;
+        }
+        let mut q = BigUint::from(self.seed);
+        for PocklingtonStep { prime, witness } in &self.steps {
+            let one = BigUint::one();
+            if *prime <= one || *witness <= one || witness >= prime {
+                return false;
+            }
+            let prime_minus_one = prime - 1_u32;
+            let (cofactor, remainder) = prime_minus_one.div_rem(&q);
+            if !remainder.is_zero() || &q * &q <= *prime {
+                return false;
+            }
+            if witness.modpow(&prime_minus_one, prime) != one {
+                return false;
+            }
+            let partial = witness.modpow(&cofactor, prime);
+            if partial.is_zero() || (partial - 1_u32).gcd(prime) != one {
+                return false;
+            }
+            q = prime.clone();
+        }
+        true
+    }
+}
+
+fn small_prime(value: u64) -> bool {
+    value >= 2
+        && (2..)
+            .take_while(|i| i * i <= value)
+            .all(|i| !value.is_multiple_of(i))
+}
+
+/// Maurer-style construction: recursively prove a prime `q` of a little over half the size,
+/// then look for `p = 2kq + 1` with the top two bits set that Pocklington's criterion proves.
+pub fn gen_provable_prime<G: Rng + ?Sized>(rng: &mut G, bit_size: u64) -> PrimeCertificate {
+    assert!(bit_size >= 2, "a prime needs at least two bits");
+    if bit_size <= SEED_SIZE {
+        let (low, high) = (3_u64 << (bit_size - 2), 1_u64 << bit_size);
+        let seed = loop {
+            let candidate = rng.gen_range(low..high) | 1;
+            if small_prime(candidate) {
+                break candidate;
+            }
+        };
+        return PrimeCertificate {
+            seed,
+            steps: vec![],
+        };
+    }
+
+    // q > 2^(ceil(bit_size / 2)) >= sqrt(p) for every p below 2^bit_size.
+    let mut certificate = gen_provable_prime(rng, bit_size.div_ceil(2) + 1);
+    let q = certificate.prime();
+    let two_q = &q << 1;
+    let low = BigUint::from(3_u32) << (bit_size - 2);
+    let high = BigUint::one() << bit_size;
+    let k_low = (&low - 1_u32).div_ceil(&two_q);
+    let k_high = (&high - 1_u32) / &two_q;
+    loop {
+        let k = rng.gen_biguint_range(&k_low, &k_high);
+        let prime = &k * &two_q + 1_u32;
+        if !trial_division(&prime) {
+            continue;
+        }
+        let witness = rng.gen_biguint_range(&BigUint::from(2_u32), &(&prime - 1_u32));
+        if witness.modpow(&(&prime - 1_u32), &prime) != BigUint::one() {
+            continue;
+        }
+        let partial = witness.modpow(&(k << 1), &prime);
+        if (partial - 1_u32).gcd(&prime).is_one() {
+            certificate.steps.push(PocklingtonStep { prime, witness });
+            break certificate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{base64_to_certificate, certificate_to_base64};
+    use crate::error::RsaError;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn generated_certificates_verify() {
+        let mut rng = ChaCha20Rng::seed_from_u64(11);
+        for bit_size in [16, 33, 128, 257, 512] {
+            let certificate = gen_provable_prime(&mut rng, bit_size);
+            assert_eq!(certificate.prime().bits(), bit_size);
+            assert!(certificate.prime().bit(bit_size - 2));
+            assert!(certificate.verify());
+        }
+    }
+
+    #[test]
+    fn tampered_certificates_fail() {
+        let mut rng = ChaCha20Rng::seed_from_u64(12);
+        let certificate = gen_provable_prime(&mut rng, 256);
+
+        let mut composite_seed = certificate.clone();
+        composite_seed.seed += 2;
+        while small_prime(composite_seed.seed) {
+            composite_seed.seed += 2;
+        }
+        assert!(!composite_seed.verify());
+
+        let mut wrong_prime = certificate.clone();
+        wrong_prime.steps.last_mut().unwrap().prime += 2_u32;
+        assert!(!wrong_prime.verify());
+
+        let mut wrong_witness = certificate.clone();
+        let last = wrong_witness.steps.last_mut().unwrap();
+        last.witness = &last.prime - 1_u32;
+        assert!(!wrong_witness.verify());
+
+        let mut broken_chain = certificate;
+        broken_chain.steps.remove(0);
+        assert!(!broken_chain.verify());
+    }
+
+    #[test]
+    fn malformed_certificates_are_rejected() {
+        let mut rng = ChaCha20Rng::seed_from_u64(36);
+        let certificate = gen_provable_prime(&mut rng, 128);
+        let encoded = certificate_to_base64(&certificate);
+        assert_eq!(base64_to_certificate(&encoded), Ok(certificate));
+
+        let truncated = &encoded[..encoded.rfind('-').unwrap()];
+        let huge_seed = format!(
+            "AQAAAAAAAAAAAA-{}",
+            &encoded[encoded.find('-').unwrap() + 1..]
+        );
+        for malformed in [truncated, "", "@@", "AQ-", &huge_seed] {
+            assert_eq!(
+                base64_to_certificate(malformed),
+                Err(RsaError::MalformedCertificate),
+                "{:?}",
+                malformed
+            );
+        }
+    }
+}
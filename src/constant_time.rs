@@ -0,0 +1,325 @@
+use crate::backend::Backend;
+use std::hint::black_box;
+
+/// Bits of exponent consumed per table lookup in `ConstantTime::modpow`.
+const WINDOW_SIZE: usize = 4;
+
+/// A number of a fixed count of 64-bit limbs, least significant first. The count is set by the
+/// operation that produced the number, never by its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixed(Vec<u64>);
+
+/// Arithmetic on `Fixed` that neither branches on nor indexes memory by the value of a limb, so
+/// its timing depends only on the widths of its inputs. Whether a modulus is odd and at least
+/// 3, and whether an inverse exists, are treated as public.
+pub struct ConstantTime;
+
+/// An odd modulus with its Montgomery constants, as in `MontgomeryContext` but computed and
+/// used in constant time.
+#[derive(Debug, Clone)]
+pub struct FixedModulus {
+    modulus: Vec<u64>,
+    /// `-n^-1 mod 2^64`
+    n_prime: u64,
+    /// `R^2 mod n`
+    r_squared: Vec<u64>,
+    /// `R mod n`
+    one: Vec<u64>,
+}
+
+impl Backend for ConstantTime {
+    type Uint = Fixed;
+    type Modulus = FixedModulus;
+
+    fn from_bytes_be(bytes: &[u8]) -> Fixed {
+        let mut limbs = vec![0; bytes.len().div_ceil(8).max(1)];
+        for (i, &byte) in bytes.iter().rev().enumerate() {
+            limbs[i / 8] |= u64::from(byte) << (8 * (i % 8));
+        }
+        Fixed(limbs)
+    }
+
+    fn to_bytes_be(value: &Fixed, len: usize) -> Option<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        let mut overflow = 0;
+        for i in 0..8 * value.0.len() {
+            let byte = (value.0[i / 8] >> (8 * (i % 8))) as u8;
+            match len.checked_sub(i + 1) {
+                Some(position) => bytes[position] = byte,
+                None => overflow |= byte,
+            }
+        }
+        (overflow == 0).then_some(bytes)
+    }
+
+    fn add(a: &Fixed, b: &Fixed) -> Fixed {
+        let mut sum: Vec<u64> = (0..a.0.len().max(b.0.len()) + 1)
+            .map(|i| limb(&a.0, i))
+            .collect();
+        add_assign(&mut sum, &b.0);
+        Fixed(sum)
+    }
+
+    fn mul(a: &Fixed, b: &Fixed) -> Fixed {
+        let mut product = vec![0; a.0.len() + b.0.len()];
+        for (i, &a_i) in a.0.iter().enumerate() {
+            let mut carry = 0;
+            for (t, &b_j) in product[i..].iter_mut().zip(&b.0) {
+                (*t, carry) = mul_add(a_i, b_j, *t, carry);
+            }
+            product[i + b.0.len()] = carry;
+        }
+        Fixed(product)
+    }
+
+    fn rem(a: &Fixed, modulus: &Fixed) -> Fixed {
+        Fixed(rem(&a.0, &modulus.0))
+    }
+
+    fn sub_mod(a: &Fixed, b: &Fixed, modulus: &Fixed) -> Fixed {
+        let mut difference: Vec<u64> = (0..modulus.0.len()).map(|i| limb(&a.0, i)).collect();
+        let borrow = sub_assign(&mut difference, &b.0);
+        let mut wrapped = difference.clone();
+        add_assign(&mut wrapped, &modulus.0);
+        select_assign(&mut difference, &wrapped, mask(borrow));
+        Fixed(difference)
+    }
+
+    fn modulus(modulus: &Fixed) -> Option<FixedModulus> {
+        let n = &modulus.0;
+        let small = n[1..].iter().all(|&limb| limb == 0) && n[0] < 3;
+        if n[0] & 1 == 0 || small {
+            return None;
+        }
+        let limbs = n.len();
+        let mut r_squared = vec![0; 2 * limbs + 1];
+        r_squared[2 * limbs] = 1;
+        let mut r = vec![0; limbs + 1];
+        r[limbs] = 1;
+        Some(FixedModulus {
+            n_prime: n_prime(n[0]),
+            r_squared: rem(&r_squared, n),
+            one: rem(&r, n),
+            modulus: n.clone(),
+        })
+    }
+
+    /// A fixed window over every bit of the exponent's width: each window costs the same
+    /// squarings, a scan of the whole table and one multiplication, even by `R mod n`.
+    fn modpow(base: &Fixed, exponent: &Fixed, modulus: &FixedModulus) -> Fixed {
+        let limbs = modulus.modulus.len();
+        let mut scratch = vec![0; limbs + 2];
+        let base = rem(&base.0, &modulus.modulus);
+        let mut table = vec![modulus.one.clone(); 1 << WINDOW_SIZE];
+        modulus.mul(&base, &modulus.r_squared, &mut table[1], &mut scratch);
+        for i in 2..table.len() {
+            let (lower, upper) = table.split_at_mut(i);
+            modulus.mul(&lower[i - 1], &lower[1], &mut upper[0], &mut scratch);
+        }
+
+        let mut result = modulus.one.clone();
+        let mut product = vec![0; limbs];
+        let mut entry = vec![0; limbs];
+        for window in (0..64 * exponent.0.len() / WINDOW_SIZE).rev() {
+            for _ in 0..WINDOW_SIZE {
+                modulus.mul(&result, &result, &mut product, &mut scratch);
+                std::mem::swap(&mut result, &mut product);
+            }
+            let bit = window * WINDOW_SIZE;
+            let index = (exponent.0[bit / 64] >> (bit % 64)) & ((1 << WINDOW_SIZE) - 1);
+            for (i, power) in table.iter().enumerate() {
+                select_assign(&mut entry, power, mask(equal(i as u64, index)));
+            }
+            modulus.mul(&result, &entry, &mut product, &mut scratch);
+            std::mem::swap(&mut result, &mut product);
+        }
+        let mut unit = vec![0; limbs];
+        unit[0] = 1;
+        modulus.mul(&result, &unit, &mut product, &mut scratch);
+        Fixed(product)
+    }
+
+    fn modinv(a: &Fixed, modulus: &Fixed) -> Option<Fixed> {
+        let (gcd, inverse) = binary_gcd(&a.0, &modulus.0);
+        let one = gcd[0] == 1 && gcd[1..].iter().all(|&limb| limb == 0);
+        one.then_some(Fixed(inverse))
+    }
+
+    fn gcd(a: &Fixed, b: &Fixed) -> Fixed {
+        Fixed(binary_gcd(&a.0, &b.0).0)
+    }
+}
+
+impl FixedModulus {
+    /// `out = a * b * R^-1 mod n` for `a, b < n`, by coarsely integrated operand scanning
+    /// (CIOS), with a final subtraction that is always computed and then kept or dropped.
+    fn mul(&self, a: &[u64], b: &[u64], out: &mut [u64], scratch: &mut [u64]) {
+        let n = &self.modulus;
+        let limbs = n.len();
+        let t = scratch;
+        t.fill(0);
+        for &b_i in b {
+            let mut carry = 0;
+            for (t_j, &a_j) in t.iter_mut().zip(a) {
+                (*t_j, carry) = mul_add(a_j, b_i, *t_j, carry);
+            }
+            let (sum, overflow) = t[limbs].overflowing_add(carry);
+            t[limbs] = sum;
+            t[limbs + 1] = u64::from(overflow);
+
+            // Add m * n so that the lowest limb becomes zero, then shift it out.
+            let m = t[0].wrapping_mul(self.n_prime);
+            let (_, mut carry) = mul_add(m, n[0], t[0], 0);
+            for j in 1..limbs {
+                (t[j - 1], carry) = mul_add(m, n[j], t[j], carry);
+            }
+            let (sum, overflow) = t[limbs].overflowing_add(carry);
+            t[limbs - 1] = sum;
+            t[limbs] = t[limbs + 1] + u64::from(overflow);
+        }
+        // The result is below 2n; subtract n unless that borrows from the top limb too.
+        out.copy_from_slice(&t[..limbs]);
+        let borrow = sub_assign(out, n);
+        let keep = mask(borrow & (t[limbs] ^ 1));
+        select_assign(out, &t[..limbs], keep);
+    }
+}
+
+/// `a mod n` for a nonzero `n`, one bit of `a` at a time: double, add the bit, and subtract `n`
+/// whenever that does not borrow.
+fn rem(a: &[u64], n: &[u64]) -> Vec<u64> {
+    let limbs = n.len();
+    let mut remainder = vec![0; limbs + 1];
+    let mut difference = vec![0; limbs + 1];
+    for bit in (0..64 * a.len()).rev() {
+        let mut carry = (a[bit / 64] >> (bit % 64)) & 1;
+        for limb in remainder.iter_mut() {
+            (*limb, carry) = (*limb << 1 | carry, *limb >> 63);
+        }
+        difference.copy_from_slice(&remainder);
+        let borrow = sub_assign(&mut difference, n);
+        select_assign(&mut remainder, &difference, mask(borrow ^ 1));
+    }
+    remainder.truncate(limbs);
+    remainder
+}
+
+/// `(gcd(a, n), a^-1 mod n)` for an odd `n`; the inverse means something only for a gcd of
+/// one. Binary extended gcd as in Möller's `mpn_sec_invert`, with `a = u * x` and `b = v * x`
+/// modulo `n` throughout: every step halves `a`, after subtracting `b` if `a` is odd and
+/// swapping first if `a < b`. Each step shortens `a` or `b` by a bit, so after twice the
+/// width in steps `a` is zero and `b` is the gcd.
+fn binary_gcd(a: &[u64], n: &[u64]) -> (Vec<u64>, Vec<u64>) {
+    assert!(n[0] & 1 == 1, "the binary gcd needs an odd modulus");
+    let limbs = n.len();
+    let mut a = rem(a, n);
+    let mut b = n.to_vec();
+    let mut u = vec![0; limbs];
+    u[0] = 1;
+    let mut v = vec![0; limbs];
+    let mut difference = vec![0; limbs];
+    for _ in 0..2 * 64 * limbs {
+        let odd = a[0] & 1;
+        difference.copy_from_slice(&a);
+        let swap = mask(odd & sub_assign(&mut difference, &b));
+        swap_assign(&mut a, &mut b, swap);
+        swap_assign(&mut u, &mut v, swap);
+
+        difference.copy_from_slice(&a);
+        sub_assign(&mut difference, &b);
+        select_assign(&mut a, &difference, mask(odd));
+        difference.copy_from_slice(&u);
+        let borrow = sub_assign(&mut difference, &v);
+        add_assign_masked(&mut difference, n, mask(borrow));
+        select_assign(&mut u, &difference, mask(odd));
+
+        // a / 2, and u / 2 mod n, which adds n first if u is odd.
+        let mut high = 0;
+        for limb in a.iter_mut().rev() {
+            (*limb, high) = (*limb >> 1 | high, *limb << 63);
+        }
+        let u_odd = mask(u[0] & 1);
+        let mut high = add_assign_masked(&mut u, n, u_odd) << 63;
+        for limb in u.iter_mut().rev() {
+            (*limb, high) = (*limb >> 1 | high, *limb << 63);
+        }
+    }
+    (b, v)
+}
+
+/// All ones for 1, zero for 0.
+fn mask(bit: u64) -> u64 {
+    black_box(bit.wrapping_neg())
+}
+
+/// 1 if `a == b`, else 0.
+fn equal(a: u64, b: u64) -> u64 {
+    let difference = a ^ b;
+    ((difference | difference.wrapping_neg()) >> 63) ^ 1
+}
+
+/// Limb `i` of `a`, zero past its width.
+fn limb(a: &[u64], i: usize) -> u64 {
+    a.get(i).copied().unwrap_or(0)
+}
+
+/// `a -= b` over the width of `a`, returning the borrow.
+fn sub_assign(a: &mut [u64], b: &[u64]) -> u64 {
+    let mut borrow = 0;
+    for (i, a_i) in a.iter_mut().enumerate() {
+        let (difference, borrow_a) = a_i.overflowing_sub(limb(b, i));
+        let (difference, borrow_b) = difference.overflowing_sub(borrow);
+        *a_i = difference;
+        borrow = u64::from(borrow_a | borrow_b);
+    }
+    borrow
+}
+
+/// `a += b` over the width of `a`, returning the carry.
+fn add_assign(a: &mut [u64], b: &[u64]) -> u64 {
+    add_assign_masked(a, b, u64::MAX)
+}
+
+/// `a += b & mask`, so that adding and not adding cost the same.
+fn add_assign_masked(a: &mut [u64], b: &[u64], mask: u64) -> u64 {
+    let mut carry = 0;
+    for (i, a_i) in a.iter_mut().enumerate() {
+        let (sum, carry_a) = a_i.overflowing_add(limb(b, i) & mask);
+        let (sum, carry_b) = sum.overflowing_add(carry);
+        *a_i = sum;
+        carry = u64::from(carry_a | carry_b);
+    }
+    carry
+}
+
+/// `a = b` where `mask` is all ones, unchanged where it is zero.
+fn select_assign(a: &mut [u64], b: &[u64], mask: u64) {
+    for (a_i, &b_i) in a.iter_mut().zip(b) {
+        *a_i ^= (*a_i ^ b_i) & mask;
+    }
+}
+
+fn swap_assign(a: &mut [u64], b: &mut [u64], mask: u64) {
+    for (a_i, b_i) in a.iter_mut().zip(b.iter_mut()) {
+        let flip = (*a_i ^ *b_i) & mask;
+        *a_i ^= flip;
+        *b_i ^= flip;
+    }
+}
+
+/// `-n^-1 mod 2^64` for an odd `n` whose lowest limb is `low`. Shared with `MontgomeryContext`.
+pub(crate) fn n_prime(low: u64) -> u64 {
+    // Newton's iteration doubles the correct low bits of n^-1 each step: 1, 2, 4, ..., 64.
+    let mut inverse: u64 = 1;
+    for _ in 0..6 {
+        inverse = inverse.wrapping_mul(2_u64.wrapping_sub(low.wrapping_mul(inverse)));
+    }
+    inverse.wrapping_neg()
+}
+
+/// `a * b + c + d` as (low, high) limbs; cannot overflow 128 bits. Shared with
+/// `MontgomeryContext`.
+pub(crate) fn mul_add(a: u64, b: u64, c: u64, d: u64) -> (u64, u64) {
+    let wide = u128::from(a) * u128::from(b) + u128::from(c) + u128::from(d);
+    (wide as u64, (wide >> 64) as u64)
+}
@@ -1,12 +1,176 @@
-use num::BigUint;
+use crate::blinding::Blinding;
+use crate::certificate::{PocklingtonStep, PrimeCertificate};
+use crate::error::RsaError;
+use crate::key::{validate_public_key, CrtParams, OtherPrimeInfo, RSAPrivateKey};
+use crate::montgomery::ContextCache;
+use clap::ArgEnum;
+use num::{BigUint, ToPrimitive};
 
 pub fn key_to_base64(key: &(BigUint, BigUint)) -> String {
     oct_to_base64(&key.0) + "-" + &oct_to_base64(&key.1)
 }
 
-pub fn base64_to_key(base64: &str) -> (BigUint, BigUint) {
-    let parts: Vec<&str> = base64.split('-').collect();
-    (base64_to_oct(parts[0]), base64_to_oct(parts[1]))
+/// Reads an `n-e` public key and rejects it unless it passes `validate_public_key`.
+pub fn base64_to_key(base64: &str) -> Result<(BigUint, BigUint), RsaError> {
+    let key = base64_to_key_unchecked(base64)?;
+    validate_public_key(&key)?;
+    Ok(key)
+}
+
+/// Reads an `n-e` public key without judging it, for tools that look into weak keys.
+pub fn base64_to_key_unchecked(base64: &str) -> Result<(BigUint, BigUint), RsaError> {
+    let fields = base64_to_fields(base64).ok_or(RsaError::MalformedKey)?;
+    match <[BigUint; 2]>::try_from(fields) {
+        Ok([n, e]) => Ok((n, e)),
+        Err(_) => Err(RsaError::MalformedKey),
+    }
+}
+
+/// Which parts of a private key `private_key_to_base64_as` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum KeyFormat {
+    /// `n-e-d-p-q-dP-dQ-qInv`, plus an `r-d-t` triple for every further prime.
+    Crt,
+    /// `n-e-d`.
+    Exponents,
+    /// `n-d`, as written by older versions.
+    Legacy,
+}
+
+/// Writes `key` in `format`, dropping whatever the format has no room for.
+pub fn private_key_to_base64_as(key: &RSAPrivateKey, format: KeyFormat) -> String {
+    match format {
+        KeyFormat::Crt => private_key_to_base64(key),
+        KeyFormat::Exponents => private_key_to_base64(&RSAPrivateKey {
+            crt: None,
+            ..key.clone()
+        }),
+        KeyFormat::Legacy => {
+            private_key_to_base64(&RSAPrivateKey::new(key.n.clone(), key.d.clone()))
+        }
+    }
+}
+
+/// Writes `n-d` for a bare key, and the RFC 8017 field order `n-e-d-p-q-dP-dQ-qInv` when the
+/// CRT values are present, followed by an `r-d-t` triple for every further prime.
+pub fn private_key_to_base64(key: &RSAPrivateKey) -> String {
+    let mut fields = vec![&key.n];
+    fields.extend(&key.e);
+    fields.push(&key.d);
+    if let Some(crt) = &key.crt {
+        fields.extend([&crt.p, &crt.q, &crt.dp, &crt.dq, &crt.qinv]);
+        for info in &crt.others {
+            fields.extend([&info.r, &info.d, &info.t]);
+        }
+    }
+    fields
+        .into_iter()
+        .map(oct_to_base64)
+        .intersperse(String::from("-"))
+        .collect()
+}
+
+/// Reads a private key in any of the layouts `private_key_to_base64` writes, without checking
+/// that its fields fit together.
+pub fn base64_to_private_key(base64: &str) -> Result<RSAPrivateKey, RsaError> {
+    let fields = base64_to_fields(base64).ok_or(RsaError::MalformedKey)?;
+    Ok(match fields.len() {
+        2 => RSAPrivateKey::new(fields[0].clone(), fields[1].clone()),
+        3 => RSAPrivateKey {
+            n: fields[0].clone(),
+            e: Some(fields[1].clone()),
+            d: fields[2].clone(),
+            crt: None,
+            blinding: Blinding::default(),
+            montgomery: ContextCache::default(),
+        },
+        count if count >= 8 && (count - 8) % 3 == 0 => RSAPrivateKey {
+            n: fields[0].clone(),
+            e: Some(fields[1].clone()),
+            d: fields[2].clone(),
+            crt: Some(CrtParams {
+                p: fields[3].clone(),
+                q: fields[4].clone(),
+                dp: fields[5].clone(),
+                dq: fields[6].clone(),
+                qinv: fields[7].clone(),
+                others: fields[8..]
+                    .chunks(3)
+                    .map(|info| OtherPrimeInfo {
+                        r: info[0].clone(),
+                        d: info[1].clone(),
+                        t: info[2].clone(),
+                    })
+                    .collect(),
+            }),
+            blinding: Blinding::default(),
+            montgomery: ContextCache::default(),
+        },
+        _ => return Err(RsaError::MalformedKey),
+    })
+}
+
+/// Writes `seed-prime-witness-prime-witness-...`, one pair per Pocklington step.
+pub fn certificate_to_base64(certificate: &PrimeCertificate) -> String {
+    let mut fields = vec![oct_to_base64(&BigUint::from(certificate.seed))];
+    for step in &certificate.steps {
+        fields.push(oct_to_base64(&step.prime));
+        fields.push(oct_to_base64(&step.witness));
+    }
+    fields.join("-")
+}
+
+/// Reads a certificate written by `certificate_to_base64`, without verifying it.
+pub fn base64_to_certificate(base64: &str) -> Result<PrimeCertificate, RsaError> {
+    let fields = base64_to_fields(base64).ok_or(RsaError::MalformedCertificate)?;
+    let Some((seed, steps)) = fields.split_first() else {
+        return Err(RsaError::MalformedCertificate);
+    };
+    if steps.len() % 2 != 0 {
+        return Err(RsaError::MalformedCertificate);
+    }
+    Ok(PrimeCertificate {
+        seed: seed.to_u64().ok_or(RsaError::MalformedCertificate)?,
+        steps: steps
+            .chunks(2)
+            .map(|step| PocklingtonStep {
+                prime: step[0].clone(),
+                witness: step[1].clone(),
+            })
+            .collect(),
+    })
+}
+
+/// The dash-separated big-endian base64 numbers of a key or certificate file, or `None` if a
+/// field is empty or not base64.
+fn base64_to_fields(base64: &str) -> Option<Vec<BigUint>> {
+    base64
+        .trim()
+        .split('-')
+        .map(|field| match base64::decode(field) {
+            Ok(bytes) if !bytes.is_empty() => Some(BigUint::from_bytes_be(&bytes)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reads a number typed on the command line: `0x`-prefixed hex, plain decimal, or otherwise
+/// the big-endian base64 used by the key files.
+pub fn parse_number(input: &str) -> Option<BigUint> {
+    let input = input.trim();
+    if let Some(hex) = input
+        .strip_prefix("0x")
+        .or_else(|| input.strip_prefix("0X"))
+    {
+        BigUint::parse_bytes(hex.as_bytes(), 16)
+    } else if !input.is_empty() && input.bytes().all(|byte| byte.is_ascii_digit()) {
+        BigUint::parse_bytes(input.as_bytes(), 10)
+    } else {
+        base64::decode(input.as_bytes())
+            .ok()
+            .filter(|bytes| !bytes.is_empty())
+            .map(|bytes| BigUint::from_bytes_be(&bytes))
+    }
 }
 
 pub fn oct_to_base64(octet: &BigUint) -> String {
@@ -32,7 +196,7 @@ pub fn split_len(string: &str, interval_length: usize) -> Vec<String> {
         return_vector.push(if string.len() >= interval_length {
             string.drain(..interval_length).collect()
         } else {
-            string.drain(..).collect()
+            std::mem::take(&mut string)
         });
     }
     return_vector
@@ -0,0 +1,158 @@
+use crate::key::{MAX_MODULUS_SIZE, MIN_MODULUS_SIZE};
+use crate::prime_check::MIN_PRIME_SIZE;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RsaError {
+    /// The public exponent is even, so it can never be invertible modulo `lambda` or `phi`.
+    EvenExponent,
+    /// The public exponent is below 3.
+    ExponentTooSmall,
+    /// The public exponent is not below the modulus.
+    ExponentTooLarge,
+    /// Fewer than two primes, or too many for the modulus size.
+    InvalidPrimeCount,
+    /// The certificates do not prove every factor of the key prime.
+    UnprovenFactors,
+    /// Input that is neither decimal, `0x` hex nor base64.
+    InvalidNumber,
+    /// The key has no public exponent, as in legacy `(n, d)` keys.
+    MissingPublicExponent,
+    /// The modulus is below 3.
+    InvalidModulus,
+    /// The modulus has at most 11 bytes, all taken by the padding of every block.
+    NoRoomForMessage,
+    /// A factor is at most 1, or the factors do not multiply to the modulus.
+    FactorMismatch,
+    /// `d * e` is not 1 modulo `lambda(n)`.
+    InvalidPrivateExponent,
+    /// A CRT exponent is not `d` reduced modulo its prime minus one.
+    CrtExponentMismatch,
+    /// `qInv` or a `t` coefficient is not the inverse it should be.
+    CrtCoefficientMismatch,
+    /// Decrypting an encrypted message did not give it back.
+    DecryptionMismatch,
+    /// A signature made with the private key did not verify under the public key.
+    SignatureMismatch,
+    /// The key is not dash-separated base64 with the expected number of fields.
+    MalformedKey,
+    /// The certificate is not dash-separated base64 with a seed and whole Pocklington steps.
+    MalformedCertificate,
+    /// The modulus is even.
+    EvenModulus,
+    /// The modulus has fewer than `MIN_MODULUS_SIZE` bits.
+    ModulusTooSmall,
+    /// The modulus has more than `MAX_MODULUS_SIZE` bits.
+    ModulusTooLarge,
+    /// The modulus is divisible by an entry of `SMALL_PRIMES`.
+    SmallFactor,
+    /// The modulus is prime.
+    PrimeModulus,
+    /// The exponents did not lead to a split of the modulus into two primes.
+    FactoringFailed,
+    /// A supplied factor is not prime.
+    CompositeFactor,
+    /// The same prime was supplied twice.
+    RepeatedFactor,
+    /// The public exponent shares a factor with `lambda(n)`, so it has no inverse.
+    ExponentNotInvertible,
+    /// A private-key result did not survive re-encryption with `e`; it was withheld, since a
+    /// faulty CRT result gives away a factor of `n`.
+    FaultDetected,
+    /// A modulus or prime of the key is even or below 3, which the constant-time arithmetic
+    /// cannot work with.
+    UnsupportedModulus,
+}
+
+impl fmt::Display for RsaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RsaError::EvenExponent => write!(f, "public exponent must be odd"),
+            RsaError::ExponentTooSmall => write!(f, "public exponent must be at least 3"),
+            RsaError::ExponentTooLarge => write!(f, "public exponent must be below the modulus"),
+            RsaError::InvalidPrimeCount => write!(
+                f,
+                "a key needs at least 2 primes, each of at least {} bits",
+                MIN_PRIME_SIZE
+            ),
+            RsaError::UnprovenFactors => write!(
+                f,
+                "the certificates do not prove every factor of the key prime"
+            ),
+            RsaError::InvalidNumber => {
+                write!(f, "expected a decimal, 0x-prefixed hex or base64 number")
+            }
+            RsaError::MissingPublicExponent => write!(f, "the key has no public exponent"),
+            RsaError::InvalidModulus => write!(f, "the modulus must be at least 3"),
+            RsaError::NoRoomForMessage => {
+                write!(
+                    f,
+                    "the modulus is too small to hold a message after padding"
+                )
+            }
+            RsaError::FactorMismatch => {
+                write!(f, "the factors of the key do not multiply to its modulus")
+            }
+            RsaError::InvalidPrivateExponent => {
+                write!(f, "d * e is not congruent to 1 modulo lambda(n)")
+            }
+            RsaError::CrtExponentMismatch => {
+                write!(f, "a CRT exponent does not match the private exponent")
+            }
+            RsaError::CrtCoefficientMismatch => {
+                write!(f, "a CRT coefficient is not the required inverse")
+            }
+            RsaError::DecryptionMismatch => {
+                write!(f, "decryption does not undo encryption")
+            }
+            RsaError::SignatureMismatch => {
+                write!(f, "a signature made with the key does not verify")
+            }
+            RsaError::MalformedKey => write!(f, "the key is not in the expected format"),
+            RsaError::MalformedCertificate => {
+                write!(f, "the certificate is not in the expected format")
+            }
+            RsaError::EvenModulus => write!(f, "the modulus must be odd"),
+            RsaError::ModulusTooSmall => {
+                write!(
+                    f,
+                    "the modulus must have at least {} bits",
+                    MIN_MODULUS_SIZE
+                )
+            }
+            RsaError::ModulusTooLarge => {
+                write!(f, "the modulus must have at most {} bits", MAX_MODULUS_SIZE)
+            }
+            RsaError::SmallFactor => write!(f, "the modulus has a small prime factor"),
+            RsaError::PrimeModulus => write!(f, "the modulus must be composite"),
+            RsaError::FactoringFailed => {
+                write!(
+                    f,
+                    "could not factor the modulus into two primes with e and d"
+                )
+            }
+            RsaError::CompositeFactor => write!(f, "a factor of the key is not prime"),
+            RsaError::RepeatedFactor => write!(f, "the factors of a key must be distinct"),
+            RsaError::ExponentNotInvertible => {
+                write!(f, "the public exponent is not invertible modulo lambda(n)")
+            }
+            RsaError::FaultDetected => {
+                write!(
+                    f,
+                    "a private-key operation failed its check and was withheld"
+                )
+            }
+            RsaError::UnsupportedModulus => {
+                write!(f, "a modulus or prime of the key is even or below 3")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RsaError {}
+
+impl From<RsaError> for std::io::Error {
+    fn from(err: RsaError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
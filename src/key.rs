@@ -0,0 +1,470 @@
+use crate::blinding::Blinding;
+use crate::error::RsaError;
+use crate::montgomery::{ContextCache, KeyContexts};
+use crate::prime_check::{
+    carmichael_lambda, decrypt_uint, encrypt_uint, is_probable_prime, miller_rabin_rounds,
+    mod_inverse, sign_uint, trial_division, verify_uint, RSAPublicKey,
+};
+use num::bigint::RandBigInt;
+use num::traits::{One, Zero};
+use num::{BigUint, Integer};
+use rand::{Rng, RngCore};
+use std::sync::Arc;
+
+/// Random bases `factor_modulus` tries. Each one splits `n` with probability at least 1/2.
+const FACTORING_TRIES: usize = 100;
+
+/// Moduli accepted by `validate_public_key`. SP 800-89 leaves the range to the application;
+/// below 1024 bits a modulus can be factored by anyone with a cluster.
+pub const MIN_MODULUS_SIZE: u64 = 1024;
+pub const MAX_MODULUS_SIZE: u64 = 16384;
+
+/// An additional prime `r` of a multi-prime key with `d mod (r - 1)` and the CRT coefficient
+/// `t`, the `OtherPrimeInfo` of RFC 8017 section 3.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtherPrimeInfo {
+    pub r: BigUint,
+    pub d: BigUint,
+    /// `(r_1 * ... * r_(i-1))^-1 mod r_i`
+    pub t: BigUint,
+}
+
+/// Chinese Remainder Theorem values of a key, as laid out in RFC 8017 section 3.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrtParams {
+    pub p: BigUint,
+    pub q: BigUint,
+    /// `d mod (p - 1)`
+    pub dp: BigUint,
+    /// `d mod (q - 1)`
+    pub dq: BigUint,
+    /// `q^-1 mod p`
+    pub qinv: BigUint,
+    /// Empty for a two-prime key.
+    pub others: Vec<OtherPrimeInfo>,
+}
+
+impl CrtParams {
+    /// `primes` starts with `p` and `q`, followed by any further primes.
+    pub fn new(primes: &[BigUint], d: &BigUint) -> Self {
+        let (p, q) = (primes[0].clone(), primes[1].clone());
+        let dp = d % (&p - 1_u32);
+        let dq = d % (&q - 1_u32);
+        let qinv = mod_inverse(&q, &p).expect("the primes must be distinct");
+        let mut product = &p * &q;
+        let mut others = Vec::with_capacity(primes.len() - 2);
+        for r in &primes[2..] {
+            others.push(OtherPrimeInfo {
+                r: r.clone(),
+                d: d % (r - 1_u32),
+                t: mod_inverse(&product, r).expect("the primes must be distinct"),
+            });
+            product *= r;
+        }
+        Self {
+            p,
+            q,
+            dp,
+            dq,
+            qinv,
+            others,
+        }
+    }
+
+    pub fn primes(&self) -> impl Iterator<Item = &BigUint> {
+        [&self.p, &self.q]
+            .into_iter()
+            .chain(self.others.iter().map(|info| &info.r))
+    }
+
+    /// The factor, exponent and coefficient checks of `RSAPrivateKey::validate`. Factors are
+    /// checked first, so nothing after them can divide by zero.
+    fn validate(&self, n: &BigUint, e: &BigUint, d: &BigUint) -> Result<(), RsaError> {
+        let one = BigUint::one();
+        if self.primes().any(|prime| *prime <= one) || self.primes().product::<BigUint>() != *n {
+            return Err(RsaError::FactorMismatch);
+        }
+        if (e * d) % carmichael_lambda(self.primes()) != one {
+            return Err(RsaError::InvalidPrivateExponent);
+        }
+        let exponents = [(&self.p, &self.dp), (&self.q, &self.dq)]
+            .into_iter()
+            .chain(self.others.iter().map(|info| (&info.r, &info.d)));
+        for (prime, exponent) in exponents {
+            if *exponent != d % (prime - 1_u32) {
+                return Err(RsaError::CrtExponentMismatch);
+            }
+        }
+        if (&self.qinv * &self.q) % &self.p != one || self.qinv >= self.p {
+            return Err(RsaError::CrtCoefficientMismatch);
+        }
+        let mut product = &self.p * &self.q;
+        for info in &self.others {
+            if (&info.t * &product) % &info.r != one || info.t >= info.r {
+                return Err(RsaError::CrtCoefficientMismatch);
+            }
+            product *= &info.r;
+        }
+        Ok(())
+    }
+}
+
+/// A private key either holds its factors, so that decryption can go through the CRT, or is a
+/// bare `(n, d)` pair as written by older versions. Every private operation of a key with `e`
+/// is blinded, see `Blinding`, and runs on `ConstantTime` with moduli the key prepares on first
+/// use. Both caches notice when the fields they depend on are edited and start over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RSAPrivateKey {
+    pub n: BigUint,
+    pub e: Option<BigUint>,
+    pub d: BigUint,
+    pub crt: Option<CrtParams>,
+    pub blinding: Blinding,
+    pub montgomery: ContextCache,
+}
+
+impl RSAPrivateKey {
+    pub fn new(n: BigUint, d: BigUint) -> Self {
+        Self {
+            n,
+            e: None,
+            d,
+            crt: None,
+            blinding: Blinding::default(),
+            montgomery: ContextCache::default(),
+        }
+    }
+
+    /// The Montgomery contexts for `n` and the private moduli, built on the first call and
+    /// again whenever `n` or the primes have changed since.
+    pub fn contexts(&self) -> Arc<KeyContexts> {
+        let primes = self.crt.iter().flat_map(|crt| crt.primes());
+        self.montgomery.get(&self.n, primes)
+    }
+
+    /// Draws blinding values from `rng` instead of a generator seeded from the OS.
+    pub fn with_blinding_rng<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        let mut blinding = Blinding::with_rng(rng);
+        blinding.set_exponent_blinding(self.blinding.exponent_blinding());
+        self.blinding = blinding;
+        self
+    }
+
+    /// Runs every private operation with `d + k * lambda(n)` for a fresh random `k`, so that
+    /// the exponent differs between operations.
+    pub fn with_exponent_blinding(mut self, enabled: bool) -> Self {
+        self.blinding.set_exponent_blinding(enabled);
+        self
+    }
+
+    pub fn from_primes(primes: Vec<BigUint>, e: BigUint, d: BigUint) -> Self {
+        let n = primes.iter().product();
+        let crt = CrtParams::new(&primes, &d);
+        Self {
+            n,
+            e: Some(e),
+            d,
+            crt: Some(crt),
+            blinding: Blinding::default(),
+            montgomery: ContextCache::default(),
+        }
+    }
+    /// Builds a key from its primes and public exponent, with `d = e^-1 mod lambda(n)` and the
+    /// CRT values derived from it. The primes are checked first, so bad input is an error and
+    /// never a panic.
+    pub fn from_prime_factors(primes: Vec<BigUint>, e: BigUint) -> Result<Self, RsaError> {
+        check_factors(&primes)?;
+        let d =
+            mod_inverse(&e, &carmichael_lambda(&primes)).ok_or(RsaError::ExponentNotInvertible)?;
+        let key = Self::from_primes(primes, e, d);
+        key.validate()?;
+        Ok(key)
+    }
+
+    /// Like `from_prime_factors`, but for sources that also supply `n` and `d`: those are kept
+    /// as given, and must agree with the primes.
+    pub fn from_components(
+        n: BigUint,
+        e: BigUint,
+        d: BigUint,
+        primes: Vec<BigUint>,
+    ) -> Result<Self, RsaError> {
+        check_factors(&primes)?;
+        if primes.iter().product::<BigUint>() != n {
+            return Err(RsaError::FactorMismatch);
+        }
+        let key = Self::from_primes(primes, e, d);
+        key.validate()?;
+        Ok(key)
+    }
+
+    /// Upgrades a key without factors to the CRT form by factoring `n` with `e` and `d`, see
+    /// `factor_modulus`. `e` is taken from the key when it has one.
+    pub fn recover_primes<G: Rng + ?Sized>(
+        &self,
+        rng: &mut G,
+        e: &BigUint,
+    ) -> Result<RSAPrivateKey, RsaError> {
+        let e = self.e.as_ref().unwrap_or(e);
+        let (p, q) = factor_modulus(rng, &self.n, e, &self.d)?;
+        let key = RSAPrivateKey::from_primes(vec![p, q], e.clone(), self.d.clone());
+        key.validate()?;
+        Ok(key)
+    }
+
+    /// Checks that the parts of the key agree with each other: the factors multiply to `n`,
+    /// `d` inverts `e` modulo `lambda(n)`, the CRT values derive from them, and a random
+    /// message survives encrypt-then-decrypt and sign-then-verify. Only the round trips can be
+    /// run on keys without factors.
+    pub fn validate(&self) -> Result<(), RsaError> {
+        let e = self.e.as_ref().ok_or(RsaError::MissingPublicExponent)?;
+        if self.n < BigUint::from(3_u32) {
+            return Err(RsaError::InvalidModulus);
+        }
+        if e.is_even() {
+            return Err(RsaError::EvenExponent);
+        }
+        if *e < BigUint::from(3_u32) {
+            return Err(RsaError::ExponentTooSmall);
+        }
+        if *e >= self.n {
+            return Err(RsaError::ExponentTooLarge);
+        }
+        if let Some(crt) = &self.crt {
+            crt.validate(&self.n, e, &self.d)?;
+        }
+        let public_key = (self.n.clone(), e.clone());
+        let message = rand::thread_rng().gen_biguint_below(&self.n);
+        let decrypted = decrypt_uint(self, &encrypt_uint(&public_key, &message))
+            .map_err(|_| RsaError::DecryptionMismatch)?;
+        if decrypted != message {
+            return Err(RsaError::DecryptionMismatch);
+        }
+        let signature = sign_uint(self, &message).map_err(|_| RsaError::SignatureMismatch)?;
+        if !verify_uint(&public_key, &message, &signature) {
+            return Err(RsaError::SignatureMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// The partial public key validation of NIST SP 800-89 section 5.3.3: `n` is odd, of an allowed
+/// size, free of the factors in `SMALL_PRIMES` and composite, and `e` is odd with `3 <= e < n`.
+pub fn validate_public_key(key: &RSAPublicKey) -> Result<(), RsaError> {
+    let (n, e) = key;
+    if n.is_even() {
+        return Err(RsaError::EvenModulus);
+    }
+    if n.bits() < MIN_MODULUS_SIZE {
+        return Err(RsaError::ModulusTooSmall);
+    }
+    if n.bits() > MAX_MODULUS_SIZE {
+        return Err(RsaError::ModulusTooLarge);
+    }
+    if !trial_division(n) {
+        return Err(RsaError::SmallFactor);
+    }
+    if is_probable_prime(n, miller_rabin_rounds(n.bits())) {
+        return Err(RsaError::PrimeModulus);
+    }
+    if e.is_even() {
+        return Err(RsaError::EvenExponent);
+    }
+    if *e < BigUint::from(3_u32) {
+        return Err(RsaError::ExponentTooSmall);
+    }
+    if e >= n {
+        return Err(RsaError::ExponentTooLarge);
+    }
+    Ok(())
+}
+
+/// At least two factors, all prime and distinct, as `CrtParams::new` requires.
+fn check_factors(primes: &[BigUint]) -> Result<(), RsaError> {
+    if primes.len() < 2 {
+        return Err(RsaError::InvalidPrimeCount);
+    }
+    for (i, prime) in primes.iter().enumerate() {
+        if !is_probable_prime(prime, miller_rabin_rounds(prime.bits())) {
+            return Err(RsaError::CompositeFactor);
+        }
+        if primes[..i].contains(prime) {
+            return Err(RsaError::RepeatedFactor);
+        }
+    }
+    Ok(())
+}
+
+/// NIST SP 800-56B appendix C.2: `d * e - 1 = 2^t * r` is a multiple of `lambda(n)`, so for a
+/// random `g` the sequence `g^r, g^2r, ...` reaches 1, and the value just before is a square
+/// root of 1 that is, with probability at least 1/2, not -1. Its gcd with `n` then splits `n`.
+/// Returns `(p, q)` with `p > q`.
+pub fn factor_modulus<G: Rng + ?Sized>(
+    rng: &mut G,
+    n: &BigUint,
+    e: &BigUint,
+    d: &BigUint,
+) -> Result<(BigUint, BigUint), RsaError> {
+    let three = BigUint::from(3_u32);
+    if *n <= three || d.is_zero() || e.is_zero() {
+        return Err(RsaError::FactoringFailed);
+    }
+    let k = d * e - 1_u32;
+    if k.is_zero() || k.is_odd() {
+        return Err(RsaError::FactoringFailed);
+    }
+    let t = k.trailing_zeros().unwrap();
+    let r = k >> t;
+    let (one, n_minus_one) = (BigUint::one(), n - 1_u32);
+    for _ in 0..FACTORING_TRIES {
+        let g = rng.gen_biguint_range(&BigUint::from(2_u32), &n_minus_one);
+        let mut y = g.modpow(&r, n);
+        if y == one || y == n_minus_one {
+            continue;
+        }
+        for _ in 0..t {
+            let x = &y * &y % n;
+            if x == one {
+                let p = (&y - 1_u32).gcd(n);
+                let q = n / &p;
+                let prime = |factor: &BigUint| {
+                    is_probable_prime(factor, miller_rabin_rounds(factor.bits()))
+                };
+                if !prime(&p) || !prime(&q) {
+                    return Err(RsaError::FactoringFailed);
+                }
+                return Ok(if p > q { (p, q) } else { (q, p) });
+            }
+            if x == n_minus_one {
+                break;
+            }
+            y = x;
+        }
+    }
+    Err(RsaError::FactoringFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prime_check::{PrimeUtils, DEFAULT_EXPONENT};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn public_key_validation_rejects_each_defect() {
+        let mut utils = PrimeUtils::with_rng(MIN_MODULUS_SIZE, ChaCha20Rng::seed_from_u64(17));
+        let ((n, e), _) = utils.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+        assert_eq!(validate_public_key(&(n.clone(), e.clone())), Ok(()));
+
+        let rejected = |n: &BigUint, e: &BigUint| validate_public_key(&(n.clone(), e.clone()));
+        assert_eq!(rejected(&(&n + 1_u32), &e), Err(RsaError::EvenModulus));
+        assert_eq!(
+            rejected(&(&n >> 1 | BigUint::one()), &e),
+            Err(RsaError::ModulusTooSmall)
+        );
+        let huge = (BigUint::one() << MAX_MODULUS_SIZE) + 1_u32;
+        assert_eq!(rejected(&huge, &e), Err(RsaError::ModulusTooLarge));
+        assert_eq!(rejected(&(&n * 17863_u32), &e), Err(RsaError::SmallFactor));
+        let prime = (BigUint::one() << 1279) - 1_u32;
+        assert_eq!(rejected(&prime, &e), Err(RsaError::PrimeModulus));
+        assert_eq!(
+            rejected(&n, &BigUint::one()),
+            Err(RsaError::ExponentTooSmall)
+        );
+        assert_eq!(
+            rejected(&n, &BigUint::from(65536_u32)),
+            Err(RsaError::EvenExponent)
+        );
+        assert_eq!(rejected(&n, &(&n + 2_u32)), Err(RsaError::ExponentTooLarge));
+    }
+
+    #[test]
+    fn keys_are_built_from_components() {
+        let component = |value: u32| BigUint::from(value);
+        let key =
+            RSAPrivateKey::from_prime_factors(vec![component(61), component(53)], component(17))
+                .unwrap();
+        assert_eq!(key.n, component(3233));
+        assert_eq!(key.d, component(413));
+        let crt = key.crt.as_ref().unwrap();
+        assert_eq!(
+            (&crt.dp, &crt.dq, &crt.qinv),
+            (&component(53), &component(49), &component(38))
+        );
+
+        // The phi-based exponent is just as valid, and is kept when supplied.
+        let supplied = RSAPrivateKey::from_components(
+            component(3233),
+            component(17),
+            component(2753),
+            vec![component(61), component(53)],
+        )
+        .unwrap();
+        assert_eq!(supplied.d, component(2753));
+
+        let import = |n: u32, e: u32, d: u32, primes: &[u32]| {
+            RSAPrivateKey::from_components(
+                component(n),
+                component(e),
+                component(d),
+                primes.iter().copied().map(component).collect(),
+            )
+        };
+        assert_eq!(
+            import(3233, 17, 414, &[61, 53]),
+            Err(RsaError::InvalidPrivateExponent)
+        );
+        assert_eq!(
+            import(3235, 17, 413, &[61, 53]),
+            Err(RsaError::FactorMismatch)
+        );
+        assert_eq!(
+            import(3721, 17, 413, &[61, 61]),
+            Err(RsaError::RepeatedFactor)
+        );
+        assert_eq!(
+            import(3233, 17, 413, &[3233]),
+            Err(RsaError::InvalidPrimeCount)
+        );
+        assert_eq!(
+            RSAPrivateKey::from_prime_factors(vec![component(61), component(55)], component(17)),
+            Err(RsaError::CompositeFactor)
+        );
+        assert_eq!(
+            RSAPrivateKey::from_prime_factors(vec![component(61), component(53)], component(3)),
+            Err(RsaError::ExponentNotInvertible)
+        );
+    }
+
+    #[test]
+    fn primes_are_recovered_from_the_exponents() {
+        let mut rng = ChaCha20Rng::seed_from_u64(24);
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        for seed in 0..4 {
+            let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(seed));
+            let (_, key) = utils.gen_key(&e).unwrap();
+            let legacy = RSAPrivateKey::new(key.n.clone(), key.d.clone());
+            let recovered = legacy.recover_primes(&mut rng, &e).unwrap();
+            assert_eq!(recovered.validate(), Ok(()));
+            let mut primes: Vec<&BigUint> = recovered.crt.as_ref().unwrap().primes().collect();
+            let mut expected: Vec<&BigUint> = key.crt.as_ref().unwrap().primes().collect();
+            primes.sort();
+            expected.sort();
+            assert_eq!(primes, expected);
+        }
+
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(25));
+        let (_, key) = utils.gen_key(&e).unwrap();
+        let wrong = RSAPrivateKey::new(key.n.clone(), &key.d + 2_u32);
+        assert_eq!(
+            wrong.recover_primes(&mut rng, &e),
+            Err(RsaError::FactoringFailed)
+        );
+        let (_, three_primes) = utils.gen_multi_prime_key(&e, 3).unwrap();
+        assert_eq!(
+            factor_modulus(&mut rng, &three_primes.n, &e, &three_primes.d).map(|_| ()),
+            Err(RsaError::FactoringFailed)
+        );
+    }
+}
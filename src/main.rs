@@ -6,14 +6,38 @@ extern crate lazy_static;
 extern crate clap;
 extern crate test;
 
-use crate::prime_check::{decrypt, encrypt, PrimeUtils};
-use bench::{bench_decrypt, bench_encrypt, bench_gen_key};
-use clap::{Parser, Subcommand};
-use convert::{base64_to_key, key_to_base64};
+use crate::audit::audit;
+use crate::batch_gcd::shared_factors;
+use crate::certificate::PrimeCertificate;
+use crate::error::RsaError;
+use crate::key::RSAPrivateKey;
+use crate::prime_check::{
+    decrypt, encrypt, find_witness, miller_rabin_rounds, PrimeKind, PrimeUtils, Totient,
+    DEFAULT_EXPONENT,
+};
+use bench::{bench_decrypt, bench_encrypt, bench_gen_key, bench_montgomery, bench_sieve};
+use clap::{CommandFactory, ErrorKind, Parser, Subcommand};
+use convert::{
+    base64_to_certificate, base64_to_key, base64_to_key_unchecked, base64_to_private_key,
+    certificate_to_base64, key_to_base64, parse_number, private_key_to_base64,
+    private_key_to_base64_as, KeyFormat,
+};
+use num::BigUint;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
 use std::io::Read;
 
+mod audit;
+mod backend;
+mod batch_gcd;
 mod bench;
+mod blinding;
+mod certificate;
+mod constant_time;
 mod convert;
+mod error;
+mod key;
+mod montgomery;
 mod prime_check;
 
 #[derive(Parser)]
@@ -26,7 +50,31 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Generate a pair of RSA keys, `id_rsa` and `id_rsa.pub` under current directory.
-    Gen,
+    Gen {
+        /// Bit length of the modulus.
+        #[clap(short, long, default_value_t = 2048, possible_values = ["2048", "3072", "4096", "8192"])]
+        bits: u64,
+        /// Public exponent; must be odd and at least 3.
+        #[clap(short, long, default_value_t = BigUint::from(DEFAULT_EXPONENT))]
+        exponent: BigUint,
+        /// Number of prime factors of the modulus.
+        #[clap(short, long, default_value_t = 2)]
+        primes: usize,
+        /// Seed for a deterministic generator; only for producing test fixtures. The search then
+        /// runs on one thread, since which worker finds a prime first varies from run to run.
+        #[clap(long, hide = true)]
+        seed: Option<u64>,
+        /// Worker threads for the prime search; defaults to the number of CPUs.
+        #[clap(short, long, conflicts_with = "seed", parse(try_from_str = parse_threads))]
+        threads: Option<usize>,
+        /// `provable` also writes a primality certificate for every factor to `id_rsa.cert`;
+        /// `safe` makes every factor `2q + 1` with `q` prime; `strong` uses Gordon's method.
+        #[clap(long, arg_enum, default_value = "probable")]
+        prime_kind: PrimeKind,
+        /// `euler` computes `d` modulo phi(n) instead of lambda(n), for older consumers.
+        #[clap(long, arg_enum, default_value = "carmichael")]
+        totient: Totient,
+    },
     /// Encrypt the input message.
     Encrypt {
         message: Option<String>,
@@ -39,6 +87,97 @@ enum Commands {
         key: Option<String>,
     },
     Bench,
+    /// Check that the certificates in `id_rsa.cert` prove every factor of `id_rsa` prime.
+    VerifyCert {
+        #[clap(short, long)]
+        cert: Option<String>,
+        #[clap(short, long)]
+        key: Option<String>,
+    },
+    /// Check that the parts of a private key are consistent with each other.
+    CheckKey {
+        #[clap(short, long)]
+        key: Option<String>,
+    },
+    /// Try cheap factoring attacks on a public key and report the weaknesses they find.
+    Audit {
+        #[clap(short, long)]
+        key: Option<String>,
+    },
+    /// Find public keys that share a prime factor, by batch GCD over all their moduli.
+    Batchgcd {
+        /// Public key files, or directories whose files are all read.
+        #[clap(required = true)]
+        paths: Vec<String>,
+    },
+    /// Factor a key stored as `(n, d)` with its public exponent and write it with its primes
+    /// and CRT values.
+    RecoverPrimes {
+        #[clap(short, long)]
+        key: Option<String>,
+        /// Used when the key does not hold its own public exponent.
+        #[clap(short, long, default_value_t = BigUint::from(DEFAULT_EXPONENT))]
+        exponent: BigUint,
+        /// Where to write the upgraded key; defaults to overwriting the input.
+        #[clap(short, long)]
+        out: Option<String>,
+    },
+    /// Build a key from its components and write it to `<out>` and `<out>.pub`. Numbers are
+    /// decimal, `0x`-prefixed hex, or base64 as in the key files.
+    ImportComponents {
+        #[clap(short)]
+        p: String,
+        #[clap(short)]
+        q: String,
+        /// Further primes of a multi-prime key.
+        #[clap(long = "prime")]
+        others: Vec<String>,
+        #[clap(short, default_value_t = DEFAULT_EXPONENT.to_string())]
+        e: String,
+        /// Checked against the primes when given.
+        #[clap(short)]
+        n: Option<String>,
+        /// Kept as given instead of computed modulo lambda(n); requires `-n`.
+        #[clap(short, requires = "n")]
+        d: Option<String>,
+        #[clap(short, long, arg_enum, default_value = "crt")]
+        format: KeyFormat,
+        #[clap(short, long, default_value = "id_rsa")]
+        out: String,
+    },
+    /// Test or generate single primes.
+    Prime {
+        #[clap(subcommand)]
+        command: PrimeCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum PrimeCommands {
+    /// Tell whether a number is prime, and if not, what shows it.
+    Test {
+        /// Decimal, `0x`-prefixed hex, or base64 as in the key files.
+        number: String,
+        /// Miller-Rabin rounds; defaults to the FIPS 186-4 count for the number's size.
+        #[clap(short, long)]
+        rounds: Option<usize>,
+    },
+    /// Print a random prime in decimal.
+    Gen {
+        #[clap(short, long)]
+        bits: u64,
+        /// Generate a safe prime `2q + 1` with `q` prime.
+        #[clap(short, long)]
+        safe: bool,
+    },
+}
+
+fn parse_threads(input: &str) -> Result<usize, String> {
+    match input.parse::<usize>() {
+        Ok(0) => Err(String::from("the prime search needs at least one thread")),
+        Ok(threads) => Ok(threads),
+        Err(err) => Err(err.to_string()),
+    }
 }
 
 fn main() -> std::io::Result<()> {
@@ -47,14 +186,44 @@ fn main() -> std::io::Result<()> {
     } else {
         'D'
     };
-    let mut checker = PrimeUtils::new(1024);
     let cli = Cli::parse();
     match cli.command {
-        Commands::Gen => {
-            let (pub_key, pri_key) = checker.gen_key();
+        Commands::Gen {
+            bits,
+            exponent,
+            primes,
+            seed,
+            threads,
+            prime_kind,
+            totient,
+        } => {
+            let utils = match seed {
+                Some(seed) => PrimeUtils::with_rng(bits, ChaCha20Rng::seed_from_u64(seed)),
+                None => PrimeUtils::new(bits),
+            };
+            let threads = match seed {
+                Some(_) => 1,
+                None => threads.unwrap_or_else(|| {
+                    std::thread::available_parallelism().map_or(1, |threads| threads.get())
+                }),
+            };
+            let mut utils = utils
+                .with_threads(threads)
+                .with_prime_kind(prime_kind)
+                .with_totient(totient);
+            let (pub_key, pri_key) = utils.gen_multi_prime_key(&exponent, primes)?;
             std::fs::write("id_rsa.pub", key_to_base64(&pub_key).as_bytes())?;
-            std::fs::write("id_rsa", key_to_base64(&pri_key).as_bytes())?;
+            std::fs::write("id_rsa", private_key_to_base64(&pri_key).as_bytes())?;
             println!("id_rsa.pub & id_rsa have been generated.");
+            if prime_kind == PrimeKind::Provable {
+                let certificates: Vec<String> = utils
+                    .certificates()
+                    .iter()
+                    .map(certificate_to_base64)
+                    .collect();
+                std::fs::write("id_rsa.cert", certificates.join("\n").as_bytes())?;
+                println!("id_rsa.cert has been generated.");
+            }
         }
         Commands::Encrypt { message, key } => {
             let message = message.unwrap_or_else(|| {
@@ -65,8 +234,8 @@ fn main() -> std::io::Result<()> {
             });
             let key_path = key.as_deref().unwrap_or("id_rsa.pub");
             let public_key =
-                base64_to_key(&String::from_utf8(std::fs::read(key_path).unwrap()).unwrap());
-            println!("{}", encrypt(&public_key, &message))
+                base64_to_key(&String::from_utf8(std::fs::read(key_path).unwrap()).unwrap())?;
+            println!("{}", encrypt(&public_key, &message)?)
         }
         Commands::Decrypt { secret, key } => {
             let secret = secret.unwrap_or_else(|| {
@@ -76,14 +245,214 @@ fn main() -> std::io::Result<()> {
                 content
             });
             let key_path = key.as_deref().unwrap_or("id_rsa");
-            let private_key =
-                base64_to_key(&String::from_utf8(std::fs::read(key_path).unwrap()).unwrap());
-            print!("\n{}", decrypt(&private_key, &secret))
+            let private_key = base64_to_private_key(
+                &String::from_utf8(std::fs::read(key_path).unwrap()).unwrap(),
+            )?;
+            print!("\n{}", decrypt(&private_key, &secret)?)
+        }
+        Commands::VerifyCert { cert, key } => {
+            let cert_path = cert.as_deref().unwrap_or("id_rsa.cert");
+            let certificates: Vec<PrimeCertificate> =
+                String::from_utf8(std::fs::read(cert_path).unwrap())
+                    .unwrap()
+                    .lines()
+                    .enumerate()
+                    .filter_map(|(line, certificate)| {
+                        base64_to_certificate(certificate)
+                            .map_err(|err| {
+                                println!("certificate on line {} is INVALID: {}", line + 1, err)
+                            })
+                            .ok()
+                    })
+                    .collect();
+            let key_path = key.as_deref().unwrap_or("id_rsa");
+            let private_key = base64_to_private_key(
+                &String::from_utf8(std::fs::read(key_path).unwrap()).unwrap(),
+            )?;
+            let mut proven: Vec<BigUint> = vec![];
+            for certificate in &certificates {
+                let prime = certificate.prime();
+                if certificate.verify() {
+                    println!("{}-bit prime: certificate verified", prime.bits());
+                    proven.push(prime);
+                } else {
+                    println!("{}-bit prime: certificate is INVALID", prime.bits());
+                }
+            }
+            let mut factors: Vec<BigUint> = private_key
+                .crt
+                .as_ref()
+                .map_or_else(Vec::new, |crt| crt.primes().cloned().collect());
+            proven.sort();
+            factors.sort();
+            if factors.is_empty() || proven != factors {
+                return Err(RsaError::UnprovenFactors.into());
+            }
+            println!("Every factor of {} is proven prime.", key_path);
+        }
+        Commands::CheckKey { key } => {
+            let key_path = key.as_deref().unwrap_or("id_rsa");
+            let private_key = base64_to_private_key(
+                &String::from_utf8(std::fs::read(key_path).unwrap()).unwrap(),
+            )?;
+            private_key.validate()?;
+            println!("{} is consistent.", key_path);
+        }
+        Commands::Audit { key } => {
+            let key_path = key.as_deref().unwrap_or("id_rsa.pub");
+            let public_key = base64_to_key_unchecked(
+                &String::from_utf8(std::fs::read(key_path).unwrap()).unwrap(),
+            )?;
+            let findings = audit(&public_key);
+            if findings.is_empty() {
+                println!("No weaknesses found in {}.", key_path);
+            }
+            for finding in findings {
+                println!("{}", finding.weakness);
+                for factor in finding.factors {
+                    println!("    factor: {}", factor);
+                }
+            }
+        }
+        Commands::Batchgcd { paths } => {
+            let mut files = vec![];
+            for path in paths {
+                if std::fs::metadata(&path)?.is_dir() {
+                    let mut entries: Vec<_> = std::fs::read_dir(&path)?
+                        .map(|entry| entry.map(|entry| entry.path()))
+                        .collect::<Result<_, _>>()?;
+                    entries.sort();
+                    files.extend(entries.into_iter().filter(|entry| entry.is_file()));
+                } else {
+                    files.push(path.into());
+                }
+            }
+            let (mut names, mut moduli) = (vec![], vec![]);
+            for file in files {
+                match base64_to_key_unchecked(&String::from_utf8_lossy(&std::fs::read(&file)?)) {
+                    Ok((n, _)) if n < BigUint::from(2_u32) => {
+                        eprintln!("Skipping {}: {}", file.display(), RsaError::InvalidModulus)
+                    }
+                    Ok((n, _)) => {
+                        names.push(file);
+                        moduli.push(n);
+                    }
+                    Err(err) => eprintln!("Skipping {}: {}", file.display(), err),
+                }
+            }
+            let pairs = shared_factors(&moduli);
+            for pair in &pairs {
+                println!(
+                    "{} and {} share the factor {}",
+                    names[pair.first].display(),
+                    names[pair.second].display(),
+                    pair.factor
+                );
+            }
+            println!(
+                "Checked {} keys, {} pairs share a factor.",
+                moduli.len(),
+                pairs.len()
+            );
+        }
+        Commands::RecoverPrimes { key, exponent, out } => {
+            let key_path = key.as_deref().unwrap_or("id_rsa");
+            let private_key = base64_to_private_key(
+                &String::from_utf8(std::fs::read(key_path).unwrap()).unwrap(),
+            )?;
+            let upgraded = private_key.recover_primes(&mut rand::thread_rng(), &exponent)?;
+            let out_path = out.as_deref().unwrap_or(key_path);
+            std::fs::write(out_path, private_key_to_base64(&upgraded).as_bytes())?;
+            println!("{} has been written with its primes.", out_path);
+        }
+        Commands::ImportComponents {
+            p,
+            q,
+            others,
+            e,
+            n,
+            d,
+            format,
+            out,
+        } => {
+            let number = |input: &str| parse_number(input).ok_or(RsaError::InvalidNumber);
+            let primes = [p, q]
+                .iter()
+                .chain(&others)
+                .map(|prime| number(prime))
+                .collect::<Result<Vec<BigUint>, _>>()?;
+            let e = number(&e)?;
+            let private_key = match (n, d) {
+                (Some(n), Some(d)) => {
+                    RSAPrivateKey::from_components(number(&n)?, e, number(&d)?, primes)?
+                }
+                (n, _) => {
+                    let key = RSAPrivateKey::from_prime_factors(primes, e)?;
+                    if n.map(|n| number(&n))
+                        .transpose()?
+                        .is_some_and(|n| n != key.n)
+                    {
+                        return Err(RsaError::FactorMismatch.into());
+                    }
+                    key
+                }
+            };
+            let public_key = (private_key.n.clone(), private_key.e.clone().unwrap());
+            std::fs::write(
+                &out,
+                private_key_to_base64_as(&private_key, format).as_bytes(),
+            )?;
+            std::fs::write(
+                format!("{}.pub", out),
+                key_to_base64(&public_key).as_bytes(),
+            )?;
+            println!("{} & {}.pub have been generated.", out, out);
+        }
+        Commands::Prime {
+            command: PrimeCommands::Test { number, rounds },
+        } => {
+            let number = parse_number(&number).ok_or(RsaError::InvalidNumber)?;
+            let rounds = rounds.unwrap_or_else(|| miller_rabin_rounds(number.bits()));
+            if number < BigUint::from(2_u32) {
+                println!("{} is neither prime nor composite", number);
+            } else if let Some(witness) = find_witness(&number, rounds) {
+                println!("composite: {}", witness);
+            } else if number.bits() <= 64 {
+                println!("prime");
+            } else {
+                println!("probably prime ({} Miller-Rabin rounds)", rounds);
+            }
+        }
+        Commands::Prime {
+            command: PrimeCommands::Gen { bits, safe },
+        } => {
+            let (kind, min_bits) = if safe {
+                ("a safe prime", 3)
+            } else {
+                ("a prime", 2)
+            };
+            if bits < min_bits {
+                Cli::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!("{} needs at least {} bits", kind, min_bits),
+                    )
+                    .exit();
+            }
+            let mut utils = PrimeUtils::new(bits);
+            let prime = if safe {
+                utils.gen_safe_prime(bits)
+            } else {
+                utils.gen_prime(bits)
+            };
+            println!("{}", prime);
         }
         Commands::Bench => {
             bench_gen_key();
+            bench_sieve();
             bench_encrypt();
             bench_decrypt();
+            bench_montgomery();
         }
     }
     Ok(())
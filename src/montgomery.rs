@@ -0,0 +1,356 @@
+use crate::backend::Backend;
+use crate::constant_time::{mul_add, n_prime, ConstantTime, FixedModulus};
+use num::traits::Zero;
+use num::{BigUint, Integer};
+use std::sync::{Arc, Mutex};
+
+/// How many public moduli `public_context` keeps contexts for.
+const PUBLIC_CONTEXTS: usize = 8;
+
+lazy_static! {
+    /// Contexts for public keys, which are bare `(n, e)` tuples with no `ContextCache` of their
+    /// own, most recently used first.
+    static ref PUBLIC: Mutex<Vec<Arc<MontgomeryContext>>> = Mutex::new(vec![]);
+}
+
+/// Montgomery arithmetic modulo an odd `n` of `k` 64-bit limbs, with `R = 2^(64k)`. Everything
+/// that depends only on `n` is computed once here, so a key that keeps its contexts pays for it
+/// once instead of on every exponentiation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MontgomeryContext {
+    n: BigUint,
+    /// `n`, little-endian limbs.
+    modulus: Vec<u64>,
+    /// `-n^-1 mod 2^64`
+    n_prime: u64,
+    /// `R^2 mod n`, which takes a value into Montgomery form in one multiplication.
+    r_squared: Vec<u64>,
+    /// `R mod n`, the Montgomery form of 1.
+    one: Vec<u64>,
+}
+
+impl MontgomeryContext {
+    /// `None` when `n` is even or below 3, where Montgomery reduction does not apply.
+    pub fn new(n: &BigUint) -> Option<Self> {
+        if n.is_even() || *n < BigUint::from(3_u32) {
+            return None;
+        }
+        let modulus = n.to_u64_digits();
+        let limbs = modulus.len();
+        let r = BigUint::from(1_u32) << (64 * limbs);
+        let context = Self {
+            n_prime: n_prime(modulus[0]),
+            r_squared: to_limbs(&(&r * &r % n), limbs),
+            one: to_limbs(&(r % n), limbs),
+            n: n.clone(),
+            modulus,
+        };
+        Some(context)
+    }
+
+    pub fn modulus(&self) -> &BigUint {
+        &self.n
+    }
+
+    /// `base^exponent mod n` with a sliding window: runs of exponent bits that start and end
+    /// with a one cost one multiplication by a precomputed odd power, and zeros cost only a
+    /// squaring.
+    pub fn modpow(&self, base: &BigUint, exponent: &BigUint) -> BigUint {
+        let limbs = self.modulus.len();
+        let mut scratch = vec![0_u64; 2 * limbs + 1];
+        let window_size = window_size(exponent.bits());
+
+        // base, base^3, base^5, ..., base^(2^window_size - 1), in Montgomery form.
+        let base = to_limbs(&(base % &self.n), limbs);
+        let mut table = vec![vec![0_u64; limbs]; 1 << (window_size - 1)];
+        let mut square = vec![0_u64; limbs];
+        self.mul(&base, &self.r_squared, &mut table[0], &mut scratch);
+        self.square(&table[0], &mut square, &mut scratch);
+        for i in 1..table.len() {
+            let (lower, upper) = table.split_at_mut(i);
+            self.mul(&lower[i - 1], &square, &mut upper[0], &mut scratch);
+        }
+
+        let mut result = self.one.clone();
+        let mut product = vec![0_u64; limbs];
+        let mut bit = exponent.bits();
+        while bit > 0 {
+            if !exponent.bit(bit - 1) {
+                self.square(&result, &mut product, &mut scratch);
+                std::mem::swap(&mut result, &mut product);
+                bit -= 1;
+                continue;
+            }
+            // The longest window of at most `window_size` bits that ends in a one.
+            let mut low = bit.saturating_sub(window_size);
+            while !exponent.bit(low) {
+                low += 1;
+            }
+            let mut index = 0;
+            for i in (low..bit).rev() {
+                self.square(&result, &mut product, &mut scratch);
+                std::mem::swap(&mut result, &mut product);
+                index = index << 1 | usize::from(exponent.bit(i));
+            }
+            self.mul(&result, &table[index >> 1], &mut product, &mut scratch);
+            std::mem::swap(&mut result, &mut product);
+            bit = low;
+        }
+        let mut unit = vec![0; limbs];
+        unit[0] = 1;
+        self.mul(&result, &unit, &mut product, &mut scratch);
+        from_limbs(&product)
+    }
+
+    /// `out = a * b * R^-1 mod n` for `a, b < n`. `scratch` holds `2k + 1` limbs.
+    fn mul(&self, a: &[u64], b: &[u64], out: &mut [u64], scratch: &mut [u64]) {
+        scratch.fill(0);
+        for (i, &a_i) in a.iter().enumerate() {
+            let mut carry = 0;
+            for (t, &b_j) in scratch[i..].iter_mut().zip(b) {
+                (*t, carry) = mul_add(a_i, b_j, *t, carry);
+            }
+            scratch[i + b.len()] = carry;
+        }
+        self.reduce(scratch, out);
+    }
+
+    /// `out = a^2 * R^-1 mod n`, computing each cross product `a_i * a_j` once and doubling.
+    fn square(&self, a: &[u64], out: &mut [u64], scratch: &mut [u64]) {
+        let limbs = a.len();
+        scratch.fill(0);
+        for i in 0..limbs {
+            let mut carry = 0;
+            for j in i + 1..limbs {
+                (scratch[i + j], carry) = mul_add(a[i], a[j], scratch[i + j], carry);
+            }
+            scratch[i + limbs] = carry;
+        }
+        let mut high_bit = 0;
+        for t in scratch[..2 * limbs].iter_mut() {
+            (*t, high_bit) = (*t << 1 | high_bit, *t >> 63);
+        }
+        let mut carry = 0;
+        for (i, &a_i) in a.iter().enumerate() {
+            let (low, high) = mul_add(a_i, a_i, scratch[2 * i], carry);
+            scratch[2 * i] = low;
+            let (sum, overflow) = scratch[2 * i + 1].overflowing_add(high);
+            scratch[2 * i + 1] = sum;
+            carry = u64::from(overflow);
+        }
+        self.reduce(scratch, out);
+    }
+
+    /// `out = t * R^-1 mod n` for `t < n^2` in `2k + 1` limbs, by separated operand scanning:
+    /// adding `m * n` at limb `i` clears that limb, so after `k` rounds `t` is a multiple of `R`.
+    fn reduce(&self, t: &mut [u64], out: &mut [u64]) {
+        let n = &self.modulus;
+        let limbs = n.len();
+        for i in 0..limbs {
+            let m = t[i].wrapping_mul(self.n_prime);
+            let mut carry = 0;
+            for (t_j, &n_j) in t[i..].iter_mut().zip(n) {
+                (*t_j, carry) = mul_add(m, n_j, *t_j, carry);
+            }
+            for t_j in t[i + limbs..].iter_mut() {
+                if carry == 0 {
+                    break;
+                }
+                let (sum, overflow) = t_j.overflowing_add(carry);
+                *t_j = sum;
+                carry = u64::from(overflow);
+            }
+        }
+        // The result is below 2n; one subtraction brings it below n.
+        let t = &t[limbs..];
+        let below_n = t[limbs] == 0 && t[..limbs].iter().rev().cmp(n.iter().rev()).is_lt();
+        if below_n {
+            out.copy_from_slice(&t[..limbs]);
+        } else {
+            let mut borrow = false;
+            for ((out_j, &t_j), &n_j) in out.iter_mut().zip(t).zip(n) {
+                let (value, borrow_a) = t_j.overflowing_sub(n_j);
+                let (value, borrow_b) = value.overflowing_sub(u64::from(borrow));
+                *out_j = value;
+                borrow = borrow_a || borrow_b;
+            }
+        }
+    }
+}
+
+/// Window size for an exponent of `bits` bits, balancing the `2^(w-1)` table entries against
+/// the multiplications they save; the thresholds are OpenSSL's.
+fn window_size(bits: u64) -> u64 {
+    match bits {
+        672.. => 6,
+        240.. => 5,
+        80.. => 4,
+        24.. => 3,
+        _ => 1,
+    }
+}
+
+fn to_limbs(value: &BigUint, limbs: usize) -> Vec<u64> {
+    let mut digits = value.to_u64_digits();
+    digits.resize(limbs, 0);
+    digits
+}
+
+fn from_limbs(limbs: &[u64]) -> BigUint {
+    let digits: Vec<u32> = limbs
+        .iter()
+        .flat_map(|&limb| [limb as u32, (limb >> 32) as u32])
+        .collect();
+    BigUint::from_slice(&digits)
+}
+
+/// The contexts of a private key, built on first use and rebuilt when the key's moduli no
+/// longer match them. Like `Blinding`, this is a cache and not part of the key's value.
+#[derive(Default)]
+pub struct ContextCache {
+    contexts: Mutex<Option<Arc<KeyContexts>>>,
+}
+
+/// `None` for moduli Montgomery reduction cannot handle.
+#[derive(Debug)]
+pub struct KeyContexts {
+    /// `n` and then the primes, as passed to `ContextCache::get`.
+    moduli: Vec<BigUint>,
+    /// For operations with the public exponent, which fall back to `BigUint::modpow`.
+    pub n: Option<MontgomeryContext>,
+    /// For the private exponentiations, on `ConstantTime`: one per prime in the order of
+    /// `CrtParams::primes`, or just `n` for a key without factors.
+    pub private: Vec<Option<FixedModulus>>,
+}
+
+impl ContextCache {
+    /// The contexts for `n` and `primes`. The moduli are compared on every call, so a key whose
+    /// fields were edited after its first private operation never runs on the old ones.
+    pub fn get<'a>(
+        &self,
+        n: &'a BigUint,
+        primes: impl Iterator<Item = &'a BigUint>,
+    ) -> Arc<KeyContexts> {
+        let moduli: Vec<&BigUint> = std::iter::once(n).chain(primes).collect();
+        let mut contexts = self.contexts.lock().unwrap();
+        if let Some(cached) = contexts.as_ref() {
+            if cached.moduli.iter().eq(moduli.iter().copied()) {
+                return cached.clone();
+            }
+        }
+        let private = if moduli.len() == 1 {
+            &moduli[..]
+        } else {
+            &moduli[1..]
+        };
+        let built = Arc::new(KeyContexts {
+            n: MontgomeryContext::new(n),
+            private: private
+                .iter()
+                .map(|modulus| {
+                    ConstantTime::modulus(&ConstantTime::from_bytes_be(&modulus.to_bytes_be()))
+                })
+                .collect(),
+            moduli: moduli.into_iter().cloned().collect(),
+        });
+        *contexts = Some(built.clone());
+        built
+    }
+}
+
+/// The context for the public modulus `n`, built on first use and kept while `n` is among the
+/// last `PUBLIC_CONTEXTS` moduli used, or `None` where Montgomery reduction does not apply.
+pub fn public_context(n: &BigUint) -> Option<Arc<MontgomeryContext>> {
+    let mut contexts = PUBLIC.lock().unwrap();
+    let context = match contexts.iter().position(|context| context.modulus() == n) {
+        Some(i) => contexts.remove(i),
+        None => Arc::new(MontgomeryContext::new(n)?),
+    };
+    contexts.insert(0, context.clone());
+    contexts.truncate(PUBLIC_CONTEXTS);
+    Some(context)
+}
+
+/// `base^exponent mod modulus`, through `context` when there is one.
+pub fn modpow(
+    context: Option<&MontgomeryContext>,
+    base: &BigUint,
+    exponent: &BigUint,
+    modulus: &BigUint,
+) -> BigUint {
+    match context {
+        Some(context) => context.modpow(base, exponent),
+        None if modulus.is_zero() => BigUint::zero(),
+        None => base.modpow(exponent, modulus),
+    }
+}
+
+impl Clone for ContextCache {
+    /// Starts empty: the contexts are cheap to rebuild and a clone may be edited.
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl PartialEq for ContextCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ContextCache {}
+
+impl std::fmt::Debug for ContextCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContextCache").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::bigint::RandBigInt;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn montgomery_modpow_matches_biguint() {
+        let mut rng = ChaCha20Rng::seed_from_u64(32);
+        for bits in [2, 63, 64, 65, 127, 128, 512, 1000, 2048] {
+            let n =
+                rng.gen_biguint(bits) | BigUint::from(1_u32) << (bits - 1) | BigUint::from(3_u32);
+            let context = MontgomeryContext::new(&n).unwrap();
+            assert_eq!(*context.modulus(), n);
+            for exponent_bits in [0, 1, 5, 17, bits] {
+                let base = rng.gen_biguint(bits + 8);
+                let exponent = rng.gen_biguint(exponent_bits);
+                assert_eq!(
+                    context.modpow(&base, &exponent),
+                    base.modpow(&exponent, &n),
+                    "{}-bit modulus, {}-bit exponent",
+                    bits,
+                    exponent_bits
+                );
+            }
+        }
+        // Values next to the modulus exercise the final subtraction.
+        let n = (BigUint::from(1_u32) << 128) - 159_u32;
+        let context = MontgomeryContext::new(&n).unwrap();
+        let base = &n - 1_u32;
+        assert_eq!(
+            context.modpow(&base, &BigUint::from(2_u32)),
+            BigUint::from(1_u32)
+        );
+        assert!(MontgomeryContext::new(&(n + 1_u32)).is_none());
+        assert!(MontgomeryContext::new(&BigUint::from(1_u32)).is_none());
+    }
+
+    #[test]
+    fn public_contexts_are_reused() {
+        let n = (BigUint::from(1_u32) << 127) - 1_u32;
+        let context = public_context(&n).unwrap();
+        assert!(Arc::ptr_eq(&context, &public_context(&n).unwrap()));
+        assert_eq!(*context.modulus(), n);
+        assert!(public_context(&(n + 1_u32)).is_none());
+    }
+}
@@ -1,9 +1,21 @@
+use crate::backend::Backend;
+use crate::blinding::EXPONENT_MASK_SIZE;
+use crate::certificate::{gen_provable_prime, PrimeCertificate};
+use crate::constant_time::{ConstantTime, Fixed};
 use crate::convert::{base64_to_oct, oct_to_base64, oct_to_str, split_len, str_to_oct};
+use crate::error::RsaError;
+use crate::key::RSAPrivateKey;
+use crate::montgomery::{modpow, public_context};
+use clap::ArgEnum;
 use num::bigint::{RandBigInt, ToBigInt};
+use num::traits::Signed;
 use num::traits::{One, RefNum, Zero};
-use num::Integer;
 use num::{BigInt, BigUint};
-use rand::{prelude::ThreadRng, thread_rng};
+use num::{Integer, ToPrimitive};
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 
 lazy_static! {
     static ref SMALL_PRIMES: [u32; 2048] = [
@@ -153,103 +165,521 @@ lazy_static! {
     ];
 }
 
-pub struct PrimeUtils {
-    bit_size: u64,
-    rng: ThreadRng,
-    current_p: Option<BigUint>,
+pub struct PrimeUtils<R: RngCore + CryptoRng = ChaCha20Rng> {
+    key_size: u64,
+    rng: R,
+    threads: usize,
+    search: Search,
+    kind: PrimeKind,
+    totient: Totient,
+    certificates: Vec<PrimeCertificate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum PrimeKind {
+    /// Random primes that pass the configured `PrimalityTest`.
+    Probable,
+    /// Primes built with `gen_provable_prime`, each with a `PrimeCertificate`.
+    Provable,
+    /// Safe primes `p = 2q + 1` with `q` prime, see `PrimeUtils::gen_safe_prime`.
+    Safe,
+    /// Gordon's strong primes, see `PrimeUtils::gen_strong_prime`.
+    Strong,
+}
+
+/// The modulus `d` inverts `e` under. Both give working keys; Carmichael's is the one FIPS
+/// 186-4 asks for and gives the smaller `d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Totient {
+    /// `lambda(n) = lcm(p - 1, q - 1, ...)`
+    Carmichael,
+    /// `phi(n) = (p - 1)(q - 1)...`, as older versions computed it.
+    Euler,
+}
+
+impl Totient {
+    /// The totient of the product of `primes`, which must be distinct.
+    pub fn of(self, primes: &[BigUint]) -> BigUint {
+        match self {
+            Totient::Carmichael => carmichael_lambda(primes),
+            Totient::Euler => primes.iter().map(|prime| prime - 1_u32).product(),
+        }
+    }
+}
+
+/// The probabilistic stage run on candidates that survive trial division or the sieve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimalityTest {
+    /// Miller-Rabin with random bases, as many rounds as `miller_rabin_rounds` asks for.
+    MillerRabin,
+    /// A base-2 strong test plus a strong Lucas test, see `baillie_psw`.
+    BailliePsw,
 }
 
-type RSAPublicKey = (BigUint, BigUint);
-type RSAPrivateKey = (BigUint, BigUint);
+impl PrimalityTest {
+    fn passes<G: Rng + ?Sized>(self, rng: &mut G, testee: &BigUint) -> bool {
+        match self {
+            PrimalityTest::MillerRabin => {
+                probable_prime(rng, testee, miller_rabin_rounds(testee.bits()))
+            }
+            PrimalityTest::BailliePsw => baillie_psw(testee),
+        }
+    }
+}
+
+/// How `search_prime` produces and tests candidates.
+#[derive(Debug, Clone, Copy)]
+struct Search {
+    sieve: bool,
+    test: PrimalityTest,
+    /// Look for `q` such that `2q + 1` is prime too, and return `2q + 1`.
+    safe: bool,
+}
+
+pub type RSAPublicKey = (BigUint, BigUint);
+
+pub const DEFAULT_EXPONENT: u32 = 65537;
+pub const MIN_PRIME_SIZE: u64 = 64;
 
 impl PrimeUtils {
-    pub fn new(bit_size: u64) -> Self {
+    /// `key_size` is the bit length of the modulus `n`, which is split between `p` and `q`.
+    pub fn new(key_size: u64) -> Self {
+        Self::with_rng(key_size, ChaCha20Rng::from_entropy())
+    }
+}
+
+impl<R: RngCore + CryptoRng> PrimeUtils<R> {
+    /// Draws all randomness from `rng`, so a seeded generator reproduces the same keys as long
+    /// as the search runs on a single thread.
+    pub fn with_rng(key_size: u64, rng: R) -> Self {
         Self {
-            bit_size,
-            rng: thread_rng(),
-            current_p: None,
+            key_size,
+            rng,
+            threads: 1,
+            search: Search {
+                sieve: true,
+                test: PrimalityTest::MillerRabin,
+                safe: false,
+            },
+            kind: PrimeKind::Probable,
+            totient: Totient::Carmichael,
+            certificates: vec![],
         }
     }
 
-    fn check_prime(&mut self) -> bool {
-        self.current_p.as_ref().map_or(false, |testee| {
-            let zero: BigUint = Zero::zero();
-            if testee.is_even() {
-                return false;
-            }
-            for prime in SMALL_PRIMES.iter() {
-                if testee % prime == zero {
-                    return false;
-                }
+    /// Spreads every prime search over `threads` workers, each with its own generator seeded
+    /// from ours.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        assert!(threads >= 1, "the prime search needs at least one thread");
+        self.threads = threads;
+        self
+    }
+
+    /// Screens candidates with `Sieve` before the probabilistic tests. Turning it off leaves
+    /// only per-candidate trial division, which is slower and kept for benchmarking.
+    pub fn with_sieve(mut self, sieve: bool) -> Self {
+        self.search.sieve = sieve;
+        self
+    }
+
+    pub fn with_primality_test(mut self, test: PrimalityTest) -> Self {
+        self.search.test = test;
+        self
+    }
+
+    pub fn with_prime_kind(mut self, kind: PrimeKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_totient(mut self, totient: Totient) -> Self {
+        self.totient = totient;
+        self
+    }
+
+    /// With `PrimeKind::Provable`, the certificates of the factors of the last generated key.
+    pub fn certificates(&self) -> &[PrimeCertificate] {
+        &self.certificates
+    }
+
+    /// Searches upwards from a fresh random odd number, so consecutive calls never share a walk.
+    /// The top two bits are always set, so the product of two such primes has exactly
+    /// `2 * bit_size` bits. The prime has the shape `with_prime_kind` asked for.
+    pub fn gen_prime(&mut self, bit_size: u64) -> BigUint {
+        assert!(bit_size >= 2, "a prime needs at least two bits");
+        match self.kind {
+            PrimeKind::Probable => self.run_search(bit_size, self.search),
+            PrimeKind::Provable => {
+                let certificate = gen_provable_prime(&mut self.rng, bit_size);
+                let prime = certificate.prime();
+                self.certificates.push(certificate);
+                prime
             }
-            let rand_num = self.rng.gen_biguint_below(testee);
-            if !fermat(testee, &rand_num) {
-                return false;
+            PrimeKind::Safe => self.gen_safe_prime(bit_size),
+            PrimeKind::Strong => self.gen_strong_prime(bit_size),
+        }
+    }
+
+    /// A prime `p = 2q + 1` whose `q` is prime as well, both with their top two bits set except
+    /// at 4 and 5 bits, where no such pair exists. The sieve screens `q` and `2q + 1` together,
+    /// so only pairs free of small factors get tested.
+    pub fn gen_safe_prime(&mut self, bit_size: u64) -> BigUint {
+        assert!(bit_size >= 3, "a safe prime needs at least three bits");
+        self.run_search(
+            bit_size,
+            Search {
+                safe: true,
+                ..self.search
+            },
+        )
+    }
+
+    /// Gordon's construction: `p - 1` has a prime factor `r` and `p + 1` a prime factor `s`,
+    /// and `r - 1` in turn has a prime factor `t`, each at least three eighths of `p`'s size.
+    pub fn gen_strong_prime(&mut self, bit_size: u64) -> BigUint {
+        self.gen_gordon_prime(bit_size).0
+    }
+
+    /// `gen_strong_prime`, also returning `r` and `s`.
+    fn gen_gordon_prime(&mut self, bit_size: u64) -> (BigUint, BigUint, BigUint) {
+        assert!(
+            bit_size >= MIN_PRIME_SIZE,
+            "a strong prime needs at least {} bits",
+            MIN_PRIME_SIZE
+        );
+        let search = self.search;
+        let aux_size = bit_size * 3 / 8;
+        let s = self.run_search(aux_size, search);
+        let t = self.run_search(aux_size, search);
+
+        // r = 2it + 1, with i about a sixteenth of the size so that r * s leaves room for p.
+        let two_t = &t << 1;
+        let mut i = self.rng.gen_biguint(bit_size / 16);
+        let r = loop {
+            i += 1_u32;
+            let r = &i * &two_t + 1_u32;
+            if trial_division(&r) && search.test.passes(&mut self.rng, &r) {
+                break r;
             }
+        };
 
-            let mut count = 0;
-            let one: BigUint = One::one();
-            let testee_minus_one: BigUint = testee.clone() - &one;
-            while count < 8 {
-                let rand_num = self.rng.gen_biguint_below(testee);
-                if rand_num == one || rand_num == testee_minus_one {
-                    continue;
+        // p0 = 2(s^(r-2) mod r)s - 1 is 1 mod r and -1 mod s, as is every p0 + 2jrs.
+        let rs = &r * &s;
+        let p0: BigUint = ((s.modpow(&(&r - 2_u32), &r) * &s) << 1) - 1_u32;
+        let step = &rs << 1;
+        let low = BigUint::from(3_u32) << (bit_size - 2);
+        let high = BigUint::one() << bit_size;
+        let j_low = (&low - &p0).div_ceil(&step);
+        let j_high = (&high - &p0) / &step;
+        loop {
+            let start = &p0 + self.rng.gen_biguint_range(&j_low, &j_high) * &step;
+            for p in candidates(start, step.clone(), false, search.sieve) {
+                if p >= high {
+                    break;
                 }
-                if !miller_rabin_single(testee, rand_num.clone()) {
-                    return false;
+                if search.test.passes(&mut self.rng, &p) {
+                    return (p, r, s);
                 }
-                count += 1;
             }
-            true
-        })
+        }
     }
 
-    pub fn gen_prime(&mut self) -> BigUint {
-        if self.current_p.is_none() {
-            let random_val = self.rng.gen_biguint(self.bit_size);
-            self.current_p = Some(if &random_val % BigUint::from(2_u32) == Zero::zero() {
-                random_val + BigUint::from(1_u32)
-            } else {
-                random_val
-            });
+    /// Runs `search_prime` on this generator, or on `threads` workers seeded from it.
+    fn run_search(&mut self, bit_size: u64, search: Search) -> BigUint {
+        if self.threads == 1 {
+            return search_prime(&mut self.rng, bit_size, search, &AtomicBool::new(false)).unwrap();
         }
+        let found = AtomicBool::new(false);
+        let workers: Vec<ChaCha20Rng> = (0..self.threads)
+            .map(|_| ChaCha20Rng::from_rng(&mut self.rng).unwrap())
+            .collect();
+        thread::scope(|scope| {
+            let handles: Vec<_> = workers
+                .into_iter()
+                .map(|mut rng| {
+                    let found = &found;
+                    scope.spawn(move || {
+                        let prime = search_prime(&mut rng, bit_size, search, found);
+                        found.store(true, Ordering::Relaxed);
+                        prime
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().unwrap())
+                .next()
+                .unwrap()
+        })
+    }
+
+    /// Draws primes until `p - 1` is coprime to `e`, so that `e` stays invertible.
+    fn gen_prime_coprime_to(&mut self, bit_size: u64, e: &BigUint) -> BigUint {
         loop {
-            self.current_p = Some(self.current_p.as_ref().unwrap() + BigUint::from(2_u32));
-            if self.check_prime() {
-                break self.current_p.as_ref().unwrap().clone();
+            let prime = self.gen_prime(bit_size);
+            if (&prime - 1_u32).gcd(e).is_one() {
+                break prime;
             }
         }
     }
 
-    pub fn gen_key(&mut self) -> (RSAPublicKey, RSAPrivateKey) {
+    pub fn gen_key(&mut self, e: &BigUint) -> Result<(RSAPublicKey, RSAPrivateKey), RsaError> {
+        self.gen_multi_prime_key(e, 2)
+    }
+
+    /// Generates a key whose modulus is the product of `prime_count` distinct primes, as in
+    /// RFC 8017 section 3.2. The modulus bits are split as evenly as possible.
+    pub fn gen_multi_prime_key(
+        &mut self,
+        e: &BigUint,
+        prime_count: usize,
+    ) -> Result<(RSAPublicKey, RSAPrivateKey), RsaError> {
         // (pub_key, pri_key)
         // ((N, e), (N, d))
-        let e = BigUint::from(65537_u32);
+        check_exponent(e, self.key_size)?;
+        check_prime_count(prime_count, self.key_size)?;
+        self.certificates.clear();
+        let count = prime_count as u64;
+        let sizes: Vec<u64> = (0..count)
+            .map(|i| self.key_size / count + u64::from(i < self.key_size % count))
+            .collect();
         loop {
-            let p = self.gen_prime();
-            let q = self.gen_prime();
-            let n = &p * &q;
-            let phi = &n - &p - &q + BigUint::from(1_u32);
-            let result = BigInt::extended_gcd(&e.to_bigint().unwrap(), &phi.to_bigint().unwrap());
-            let (mut x, d) = (result.x, result.gcd);
-            if d == One::one() {
-                if x < Zero::zero() {
-                    let k = (-&x).to_biguint().unwrap() / &phi + BigUint::from(1_u32);
-                    x += (k * &phi).to_bigint().unwrap();
-                }
-                let x = x.to_biguint().unwrap();
-                // println!("{}", &e * &x % &phi);
-                assert!(self.test_key(&phi, &e, &x));
-                break ((n.clone(), e), (n, x));
+            let mut primes: Vec<BigUint> = Vec::with_capacity(prime_count);
+            for &bit_size in &sizes {
+                let prime = loop {
+                    let prime = self.gen_prime_coprime_to(bit_size, e);
+                    if primes
+                        .iter()
+                        .all(|other| primes_far_apart(other, &prime, bit_size))
+                    {
+                        break prime;
+                    }
+                };
+                primes.push(prime);
+            }
+            let n: BigUint = primes.iter().product();
+            // The top two bits only pin the length of a two-prime product.
+            if n.bits() != self.key_size {
+                continue;
+            }
+            let totient = self.totient.of(&primes);
+            let d = mod_inverse(e, &totient).unwrap();
+            assert!(self.test_key(&totient, e, &d));
+            // Drop the certificates of primes that were discarded along the way.
+            self.certificates
+                .retain(|certificate| primes.contains(&certificate.prime()));
+            let private_key = RSAPrivateKey::from_primes(primes, e.clone(), d);
+            // Pairwise consistency test, FIPS 186-4 section 5.1.
+            private_key
+                .validate()
+                .expect("a freshly generated key must be consistent");
+            break Ok(((n, e.clone()), private_key));
+        }
+    }
+
+    pub fn test_key(&self, totient: &BigUint, e: &BigUint, d: &BigUint) -> bool {
+        e * d % totient == One::one()
+    }
+}
+
+/// How far a search walks from its random start before drawing a new one.
+const SEARCH_RANGE: u64 = 1 << 16;
+
+/// Residues of a search start and step modulo every entry of `SMALL_PRIMES`, so candidates
+/// `start + index * step` are screened with word arithmetic instead of bignum division.
+struct Sieve {
+    start: BigUint,
+    step: BigUint,
+    residues: Vec<(u64, u64)>,
+    index: u64,
+    safe: bool,
+}
+
+impl Sieve {
+    /// Walks `SEARCH_RANGE / 2` steps from `start`, which must be odd, by `step`, which must be
+    /// even. With `safe`, candidates `c` for which `2c + 1` has a small factor are skipped as well.
+    fn new(start: BigUint, step: BigUint, safe: bool) -> Self {
+        let residues = SMALL_PRIMES
+            .iter()
+            .map(|&prime| {
+                let residue = |value: &BigUint| (value % prime).to_u64().unwrap();
+                (residue(&start), residue(&step))
+            })
+            .collect();
+        Self {
+            start,
+            step,
+            residues,
+            index: 0,
+            safe,
+        }
+    }
+}
+
+impl Iterator for Sieve {
+    type Item = BigUint;
+
+    fn next(&mut self) -> Option<BigUint> {
+        while self.index < SEARCH_RANGE / 2 {
+            let index = self.index;
+            self.index += 1;
+            if SMALL_PRIMES
+                .iter()
+                .zip(&self.residues)
+                .all(|(&prime, &(start, step))| {
+                    let (prime, candidate) = (u64::from(prime), start + index * step);
+                    !candidate.is_multiple_of(prime)
+                        && !(self.safe && (2 * candidate + 1).is_multiple_of(prime))
+                })
+            {
+                return Some(&self.start + index * &self.step);
+            }
+        }
+        None
+    }
+}
+
+/// The walk `start + index * step` with candidates that have a small factor left out, screened
+/// by `Sieve` or, without `sieve`, by bignum trial division. See `Sieve::new` for `safe`.
+fn candidates(
+    start: BigUint,
+    step: BigUint,
+    safe: bool,
+    sieve: bool,
+) -> Box<dyn Iterator<Item = BigUint>> {
+    if sieve {
+        return Box::new(Sieve::new(start, step, safe));
+    }
+    Box::new(
+        (0..SEARCH_RANGE / 2)
+            .map(move |index| &start + index * &step)
+            .filter(move |candidate| {
+                trial_division(candidate) && !(safe && !trial_division(&((candidate << 1) + 1_u32)))
+            }),
+    )
+}
+
+/// The smallest entry of `SMALL_PRIMES` that divides `testee`.
+pub(crate) fn small_factor(testee: &BigUint) -> Option<u32> {
+    SMALL_PRIMES
+        .iter()
+        .copied()
+        .find(|&prime| (testee % prime).is_zero())
+}
+
+pub(crate) fn trial_division(testee: &BigUint) -> bool {
+    let zero: BigUint = Zero::zero();
+    if testee.is_even() {
+        return false;
+    }
+    for prime in SMALL_PRIMES.iter() {
+        if testee % prime == zero {
+            return false;
+        }
+    }
+    true
+}
+
+/// `rounds` Miller-Rabin rounds with bases drawn uniformly from `[2, testee - 2]`. `testee`
+/// must be odd and above 3.
+fn probable_prime<G: Rng + ?Sized>(rng: &mut G, testee: &BigUint, rounds: usize) -> bool {
+    let (low, high) = (BigUint::from(2_u32), testee - 1_u32);
+    (0..rounds).all(|_| miller_rabin_single(testee, rng.gen_biguint_range(&low, &high)))
+}
+
+fn check_prime<G: Rng + ?Sized>(rng: &mut G, testee: &BigUint) -> bool {
+    trial_division(testee) && probable_prime(rng, testee, miller_rabin_rounds(testee.bits()))
+}
+
+/// One worker of `PrimeUtils::gen_prime`. Gives up with `None` once `stop` is set by another
+/// worker. Without `search.sieve`, every candidate goes through bignum trial division instead.
+/// With `search.safe`, the walk is over `q` of one bit less and `2q + 1` is returned.
+fn search_prime<G: Rng + ?Sized>(
+    rng: &mut G,
+    bit_size: u64,
+    search: Search,
+    stop: &AtomicBool,
+) -> Option<BigUint> {
+    let safe = search.safe;
+    let bit_size = if safe { bit_size - 1 } else { bit_size };
+    if bit_size <= SMALL_SEARCH_SIZE {
+        return Some(small_prime(rng, bit_size, safe));
+    }
+    loop {
+        let mut start = rng.gen_biguint(bit_size);
+        start.set_bit(bit_size - 1, true);
+        start.set_bit(bit_size - 2, true);
+        start.set_bit(0, true);
+        for candidate in candidates(start, BigUint::from(2_u32), safe, search.sieve) {
+            if stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            if candidate.bits() > bit_size {
+                break;
+            }
+            if !search.test.passes(rng, &candidate) {
+                continue;
+            }
+            if !safe {
+                return Some(candidate);
+            }
+            let prime = (candidate << 1) + 1_u32;
+            if search.test.passes(rng, &prime) {
+                return Some(prime);
             }
         }
     }
+}
+
+/// Candidates of at most this many bits may be entries of `SMALL_PRIMES` themselves, which the
+/// sieve and trial division would throw away, so `small_prime` handles them instead.
+const SMALL_SEARCH_SIZE: u64 = 15;
+
+/// A uniformly random prime of `bit_size` bits, at most `SMALL_SEARCH_SIZE`, with its top two
+/// bits set. With `safe`, this is a `q` for which `2q + 1` is prime too, and `2q + 1` is returned;
+/// no safe prime of 4 or 5 bits has its top two bits set, so there only the top bit is.
+fn small_prime<G: Rng + ?Sized>(rng: &mut G, bit_size: u64, safe: bool) -> BigUint {
+    let high = 1_u64 << bit_size;
+    let qualifies =
+        |candidate: &u64| is_prime_u64(*candidate) && !(safe && !is_prime_u64(2 * candidate + 1));
+    let mut primes: Vec<u64> = (3 << (bit_size - 2)..high).filter(qualifies).collect();
+    if primes.is_empty() {
+        primes = (high >> 1..high).filter(qualifies).collect();
+    }
+    let prime = primes[rng.gen_range(0..primes.len())];
+    BigUint::from(if safe { 2 * prime + 1 } else { prime })
+}
+
+/// The exponent must be odd and at least 3. Every generated modulus has exactly `key_size`
+/// bits, so `e` is below `n` whenever it has fewer bits than that.
+fn check_exponent(e: &BigUint, key_size: u64) -> Result<(), RsaError> {
+    if e.is_even() {
+        Err(RsaError::EvenExponent)
+    } else if *e < BigUint::from(3_u32) {
+        Err(RsaError::ExponentTooSmall)
+    } else if e.bits() >= key_size {
+        Err(RsaError::ExponentTooLarge)
+    } else {
+        Ok(())
+    }
+}
 
-    pub fn test_key(&self, phi: &BigUint, e: &BigUint, d: &BigUint) -> bool {
-        e * d % phi == One::one()
+/// Every prime needs at least `MIN_PRIME_SIZE` bits.
+fn check_prime_count(prime_count: usize, key_size: u64) -> Result<(), RsaError> {
+    if prime_count < 2 || key_size / (prime_count as u64) < MIN_PRIME_SIZE {
+        Err(RsaError::InvalidPrimeCount)
+    } else {
+        Ok(())
     }
 }
 
+/// FIPS 186-4 B.3.3 step 5.4: `|p - q|` must exceed `2^(nlen / 2 - 100)`, otherwise `n`
+/// falls to Fermat factorisation. `bit_size` is the size of the smaller prime.
+fn primes_far_apart(p: &BigUint, q: &BigUint, bit_size: u64) -> bool {
+    let distance = if p > q { p - q } else { q - p };
+    distance > BigUint::one() << bit_size.saturating_sub(100)
+}
+
 fn get_rank<T>(testee: &T) -> T
 where
     T: RefNum<T> + From<u32> + Ord + Clone,
@@ -265,30 +695,178 @@ where
     num
 }
 
-fn encrypt_uint(private_key: &RSAPublicKey, message: &BigUint) -> BigUint {
-    let (n, e) = private_key;
-    message.modpow(e, n)
+pub(crate) fn encrypt_uint(public_key: &RSAPublicKey, message: &BigUint) -> BigUint {
+    let (n, e) = public_key;
+    modpow(public_context(n).as_deref(), message, e, n)
 }
 
-fn decrypt_uint(public_key: &RSAPrivateKey, secret: &BigUint) -> BigUint {
-    let (n, d) = public_key;
-    secret.modpow(d, n)
+/// The private-key operation, blinded with the key's `Blinding` and checked by re-applying `e`
+/// whenever the key knows `e`.
+pub(crate) fn decrypt_uint(
+    private_key: &RSAPrivateKey,
+    secret: &BigUint,
+) -> Result<BigUint, RsaError> {
+    decrypt_uint_with_fault(private_key, secret, |m1| m1)
 }
 
-pub fn encrypt(public_key: &RSAPublicKey, message: &str) -> String {
-    let parts = split_len(message, 245);
-    parts
+/// `decrypt_uint`, with `fault` applied to the first CRT half so tests can simulate a glitch.
+fn decrypt_uint_with_fault<F: Fn(Fixed) -> Fixed>(
+    private_key: &RSAPrivateKey,
+    secret: &BigUint,
+    fault: F,
+) -> Result<BigUint, RsaError> {
+    let n = &private_key.n;
+    if *n < BigUint::from(3_u32) {
+        return Err(RsaError::InvalidModulus);
+    }
+    if n.is_even() {
+        return Err(RsaError::UnsupportedModulus);
+    }
+    let contexts = private_key.contexts();
+    let moduli = &contexts.private;
+    let Some(e) = &private_key.e else {
+        return private_exponentiation::<ConstantTime, F>(private_key, moduli, secret, fault);
+    };
+    let (blind, unblind) = private_key.blinding.next_factors(n, e);
+    let blinded = secret * blind % n;
+    let message = private_exponentiation::<ConstantTime, F>(private_key, moduli, &blinded, fault)?
+        * unblind
+        % n;
+    // Bellcore: a result that is right modulo one prime only is caught here, before the caller
+    // could take the gcd of its error with n.
+    if modpow(contexts.n.as_ref(), &message, e, n) != secret % n {
+        return Err(RsaError::FaultDetected);
+    }
+    Ok(message)
+}
+
+/// `secret^d mod n` on `B`, through the CRT when the key has its factors, with `moduli` as in
+/// `KeyContexts::private`. With exponent blinding, each exponent gets a random multiple of its
+/// prime minus one, or of `e * d - 1` (a multiple of `lambda(n)`) for a key without factors.
+///
+/// Every number enters `B` padded to a width that follows from the key size alone; the
+/// conversions out of `BigUint` are not constant-time themselves.
+fn private_exponentiation<B: Backend, F: Fn(B::Uint) -> B::Uint>(
+    private_key: &RSAPrivateKey,
+    moduli: &[Option<B::Modulus>],
+    secret: &BigUint,
+    fault: F,
+) -> Result<BigUint, RsaError> {
+    let blinding = &private_key.blinding;
+    let moduli: Vec<&B::Modulus> = moduli
+        .iter()
+        .map(Option::as_ref)
+        .collect::<Option<_>>()
+        .ok_or(RsaError::UnsupportedModulus)?;
+    let len = |value: &BigUint| value.bits().div_ceil(8) as usize;
+    let mask_len = if blinding.exponent_blinding() {
+        EXPONENT_MASK_SIZE.div_ceil(8) as usize
+    } else {
+        0
+    };
+    let secret = to_backend::<B>(secret, len(&private_key.n));
+    match &private_key.crt {
+        Some(crt) => {
+            // RFC 8017 section 5.1.2, step 2.b.
+            let exponent = |d: &BigUint, prime: &BigUint| {
+                let d = blinding.mask_exponent(d, &(prime - 1_u32));
+                to_backend::<B>(&d, len(prime) + mask_len)
+            };
+            let [p, q, qinv] = [&crt.p, &crt.q, &crt.qinv]
+                .map(|value| to_backend::<B>(value, len(&crt.p).max(len(&crt.q))));
+            let m1 = fault(B::modpow(&secret, &exponent(&crt.dp, &crt.p), moduli[0]));
+            let m2 = B::modpow(&secret, &exponent(&crt.dq, &crt.q), moduli[1]);
+            let h = B::rem(&B::mul(&B::sub_mod(&m1, &B::rem(&m2, &p), &p), &qinv), &p);
+            let mut message = B::add(&m2, &B::mul(&h, &q));
+            // Step 2.b.v: fold in the remaining primes one at a time.
+            let mut product = B::mul(&p, &q);
+            let mut message_len = len(&crt.p) + len(&crt.q);
+            for (info, modulus) in crt.others.iter().zip(&moduli[2..]) {
+                let [r, t] = [&info.r, &info.t].map(|value| to_backend::<B>(value, len(&info.r)));
+                let mi = B::modpow(&secret, &exponent(&info.d, &info.r), modulus);
+                let h = B::rem(&B::mul(&B::sub_mod(&mi, &B::rem(&message, &r), &r), &t), &r);
+                message = B::add(&message, &B::mul(&product, &h));
+                product = B::mul(&product, &r);
+                message_len += len(&info.r);
+            }
+            // The result is below the product of the primes, which fits in their lengths.
+            Ok(from_backend::<B>(&message, message_len))
+        }
+        None => {
+            let (d, d_len) = match &private_key.e {
+                Some(e) => (
+                    blinding.mask_exponent(&private_key.d, &(e * &private_key.d - 1_u32)),
+                    len(&private_key.n) + len(e) + mask_len,
+                ),
+                None => (private_key.d.clone(), len(&private_key.n)),
+            };
+            let d = to_backend::<B>(&d, d_len);
+            let message = B::modpow(&secret, &d, moduli[0]);
+            Ok(from_backend::<B>(&message, len(&private_key.n)))
+        }
+    }
+}
+
+/// `value` on `B`, padded to `len` bytes so that its width does not depend on its size.
+fn to_backend<B: Backend>(value: &BigUint, len: usize) -> B::Uint {
+    let bytes = value.to_bytes_be();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    B::from_bytes_be(&padded)
+}
+
+/// `value`, which must fit in `len` bytes, back as a `BigUint`.
+fn from_backend<B: Backend>(value: &B::Uint, len: usize) -> BigUint {
+    BigUint::from_bytes_be(&B::to_bytes_be(value, len).unwrap())
+}
+
+/// Textbook signature: the private-key operation applied to `message`.
+pub(crate) fn sign_uint(
+    private_key: &RSAPrivateKey,
+    message: &BigUint,
+) -> Result<BigUint, RsaError> {
+    decrypt_uint(private_key, message)
+}
+
+pub(crate) fn verify_uint(
+    public_key: &RSAPublicKey,
+    message: &BigUint,
+    signature: &BigUint,
+) -> bool {
+    encrypt_uint(public_key, signature) == *message
+}
+
+/// Bytes of PKCS#1 v1.5 padding, which `block_size` leaves out of every block.
+const PADDING_SIZE: usize = 11;
+
+/// Bytes of plaintext per block, leaving the 11 bytes PKCS#1 v1.5 reserves so that
+/// every block stays below `n`.
+fn block_size(public_key: &RSAPublicKey) -> Result<usize, RsaError> {
+    let (n, _) = public_key;
+    match (n.bits().div_ceil(8) as usize).checked_sub(PADDING_SIZE) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(RsaError::NoRoomForMessage),
+    }
+}
+
+/// Looks up the context for `n` with `public_context` once per call, so repeated calls under
+/// the same key reuse it, and shares it between the blocks of the message.
+pub fn encrypt(public_key: &RSAPublicKey, message: &str) -> Result<String, RsaError> {
+    let (n, e) = public_key;
+    let context = public_context(n);
+    let parts = split_len(message, block_size(public_key)?);
+    Ok(parts
         .iter()
-        .map(|message| oct_to_base64(&encrypt_uint(public_key, &str_to_oct(message))))
+        .map(|message| oct_to_base64(&modpow(context.as_deref(), &str_to_oct(message), e, n)))
         .intersperse(String::from("-"))
-        .collect()
+        .collect())
 }
 
-pub fn decrypt(private_key: &RSAPrivateKey, secret: &str) -> String {
+pub fn decrypt(private_key: &RSAPrivateKey, secret: &str) -> Result<String, RsaError> {
     let parts = secret.split('-');
     parts
         .into_iter()
-        .map(|secret| oct_to_str(decrypt_uint(private_key, &base64_to_oct(secret))))
+        .map(|secret| decrypt_uint(private_key, &base64_to_oct(secret)).map(oct_to_str))
         .collect()
 }
 
@@ -302,11 +880,11 @@ pub fn miller_rabin_single(testee: &BigUint, base: BigUint) -> bool {
     let two = &one + &one;
     let testee_minus_one = testee - &one;
     let mut intermediate = base.modpow(&exp, testee);
-    if intermediate == one {
+    if intermediate == one || intermediate == testee_minus_one {
         return true;
     }
     exp *= &two;
-    while &exp <= testee {
+    while exp < testee_minus_one {
         intermediate = &intermediate * &intermediate % testee;
         if intermediate == testee_minus_one {
             return true;
@@ -316,33 +894,238 @@ pub fn miller_rabin_single(testee: &BigUint, base: BigUint) -> bool {
     false
 }
 
-pub fn quick_pow<T>(base: T, mut exp: T, prime: Option<T>) -> T
-where
-    T: RefNum<T> + From<u32> + Ord,
-    for<'a> &'a T: RefNum<T>,
-{
-    let mut temp_val = if let Some(prime) = &prime {
-        base % prime
+/// Baillie-PSW: a strong probable-prime test to base 2 followed by a strong Lucas
+/// probable-prime test with Selfridge's parameters. No composite passing both is known.
+pub fn baillie_psw(testee: &BigUint) -> bool {
+    let two = BigUint::from(2_u32);
+    if *testee < two {
+        return false;
+    }
+    if *testee == two {
+        return true;
+    }
+    if testee.is_even() || !miller_rabin_single(testee, two) {
+        return false;
+    }
+    strong_lucas_probable_prime(testee)
+}
+
+/// Strong Lucas test with Selfridge's method A: `D` is the first of 5, -7, 9, -11, ... with
+/// Jacobi symbol `(D/n) = -1`, `P = 1` and `Q = (1 - D) / 4`. `testee` must be odd.
+pub fn strong_lucas_probable_prime(testee: &BigUint) -> bool {
+    if testee.sqrt().pow(2) == *testee {
+        // No suitable `D` exists for a perfect square.
+        return false;
+    }
+    let n = testee.to_bigint().unwrap();
+    let mut d = BigInt::from(5);
+    loop {
+        match jacobi(&d, testee) {
+            -1 => break,
+            0 if d.magnitude() != testee => return false,
+            _ => {}
+        }
+        d = if d.is_positive() {
+            -(d + 2_u32)
+        } else {
+            -d + 2_u32
+        };
+    }
+    let q: BigInt = (BigInt::one() - &d) / 4_i32;
+    let reduce = |value: BigInt| value.mod_floor(&n);
+    let halve = |value: BigInt| {
+        let value = if value.is_odd() { value + &n } else { value };
+        reduce(value / 2_u32)
+    };
+
+    // n + 1 = k * 2^s with k odd.
+    let n_plus_one = testee + 1_u32;
+    let s = n_plus_one.trailing_zeros().unwrap();
+    let k = &n_plus_one >> s;
+
+    // Walk the bits of k from the top, keeping U_j, V_j and Q^j for the prefix j read so far.
+    let (mut u, mut v, mut q_power) = (BigInt::one(), BigInt::one(), reduce(q.clone()));
+    for bit in (0..k.bits() - 1).rev() {
+        u = reduce(&u * &v);
+        v = reduce(&v * &v - &q_power * 2_u32);
+        q_power = reduce(&q_power * &q_power);
+        if k.bit(bit) {
+            let (next_u, next_v) = (halve(&u + &v), halve(&d * &u + &v));
+            u = next_u;
+            v = next_v;
+            q_power = reduce(&q_power * &q);
+        }
+    }
+    if u.is_zero() || v.is_zero() {
+        return true;
+    }
+    for _ in 1..s {
+        v = reduce(&v * &v - &q_power * 2_u32);
+        if v.is_zero() {
+            return true;
+        }
+        q_power = reduce(&q_power * &q_power);
+    }
+    false
+}
+
+/// Jacobi symbol `(a/n)` for odd positive `n`.
+fn jacobi(a: &BigInt, n: &BigUint) -> i8 {
+    let mut a = a.mod_floor(&n.to_bigint().unwrap()).to_biguint().unwrap();
+    let mut n = n.clone();
+    let mut result = 1;
+    while !a.is_zero() {
+        let twos = a.trailing_zeros().unwrap();
+        a >>= twos;
+        let n_mod_8 = (&n % 8_u32).to_u8().unwrap();
+        if twos % 2 == 1 && (n_mod_8 == 3 || n_mod_8 == 5) {
+            result = -result;
+        }
+        if (&a % 4_u32).to_u8() == Some(3) && n_mod_8 % 4 == 3 {
+            result = -result;
+        }
+        std::mem::swap(&mut a, &mut n);
+        a %= &n;
+    }
+    if n.is_one() {
+        result
     } else {
-        base
+        0
+    }
+}
+
+/// Whether `testee` is prime: exact below 2^64, probabilistic above.
+pub fn is_prime(testee: &BigUint, test: PrimalityTest) -> bool {
+    match testee.to_u64() {
+        Some(small) => is_prime_u64(small),
+        None => trial_division(testee) && test.passes(&mut rand::thread_rng(), testee),
+    }
+}
+
+/// Trial division by `SMALL_PRIMES`, then `rounds` Miller-Rabin rounds with random bases.
+/// Values below 2^64 are answered exactly by `is_prime_u64` instead.
+pub fn is_probable_prime(testee: &BigUint, rounds: usize) -> bool {
+    match testee.to_u64() {
+        Some(small) => is_prime_u64(small),
+        None => trial_division(testee) && probable_prime(&mut rand::thread_rng(), testee, rounds),
+    }
+}
+
+/// Miller-Rabin rounds for a random candidate of `bit_size` bits, from FIPS 186-4 Table C.3
+/// (M-R tests only). Smaller sizes get the 41 rounds the table asks of auxiliary primes.
+/// Adversarial inputs deserve more rounds than this.
+pub fn miller_rabin_rounds(bit_size: u64) -> usize {
+    match bit_size {
+        1536.. => 4,
+        1024.. => 5,
+        512.. => 7,
+        _ => 41,
+    }
+}
+
+/// Bases for `is_prime_u64`: the first twelve primes leave no strong pseudoprime below
+/// 3.3 * 10^24, which covers every `u64`.
+const U64_WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Deterministic Miller-Rabin over the fixed bases in `U64_WITNESSES`.
+pub fn is_prime_u64(testee: u64) -> bool {
+    if testee < 2 {
+        return false;
+    }
+    for witness in U64_WITNESSES {
+        if testee.is_multiple_of(witness) {
+            return testee == witness;
+        }
+    }
+    u64_witness(testee).is_none()
+}
+
+/// The first base in `U64_WITNESSES` that `testee`, odd and above 37, fails to be a strong
+/// probable prime to.
+fn u64_witness(testee: u64) -> Option<u64> {
+    let mul_mod = |a: u64, b: u64| (u128::from(a) * u128::from(b) % u128::from(testee)) as u64;
+    let pow_mod = |mut base: u64, mut exp: u64| {
+        let mut result = 1;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = mul_mod(result, base);
+            }
+            base = mul_mod(base, base);
+            exp >>= 1;
+        }
+        result
     };
-    let mut return_val: T = 1.into();
-    let one: T = 1.into();
-    let two: T = 2.into();
-    while exp >= one {
-        if let Some(prime) = &prime {
-            temp_val = temp_val % prime;
+    let rank = (testee - 1).trailing_zeros();
+    let odd_part = (testee - 1) >> rank;
+    U64_WITNESSES.into_iter().find(|&witness| {
+        let mut intermediate = pow_mod(witness, odd_part);
+        if intermediate == 1 || intermediate == testee - 1 {
+            return false;
         }
-        if &exp % &two == one {
-            return_val = return_val * &temp_val;
+        for _ in 1..rank {
+            intermediate = mul_mod(intermediate, intermediate);
+            if intermediate == testee - 1 {
+                return false;
+            }
         }
-        temp_val = &temp_val * &temp_val;
-        exp = exp / &two;
+        true
+    })
+}
+
+/// Evidence that a number is composite, as found by `find_witness`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Witness {
+    /// A prime from `SMALL_PRIMES` that divides the number.
+    Factor(u32),
+    /// A base the number is not a strong probable prime to.
+    MillerRabin(BigUint),
+}
+
+impl std::fmt::Display for Witness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Witness::Factor(prime) => write!(f, "divisible by {}", prime),
+            Witness::MillerRabin(base) => write!(f, "Miller-Rabin witness {}", base),
+        }
+    }
+}
+
+/// Like `is_probable_prime`, but keeps what showed `testee` composite. `None` means prime
+/// (exactly so below 2^64). `testee` must be at least 2.
+pub fn find_witness(testee: &BigUint, rounds: usize) -> Option<Witness> {
+    assert!(
+        *testee >= BigUint::from(2_u32),
+        "0 and 1 are neither prime nor composite"
+    );
+    if let Some(prime) = small_factor(testee) {
+        return (*testee != BigUint::from(prime)).then_some(Witness::Factor(prime));
     }
-    if let Some(prime) = &prime {
-        return_val % prime
+    if let Some(small) = testee.to_u64() {
+        return u64_witness(small).map(|base| Witness::MillerRabin(base.into()));
+    }
+    let mut rng = rand::thread_rng();
+    let (low, high) = (BigUint::from(2_u32), testee - 1_u32);
+    (0..rounds)
+        .map(|_| rng.gen_biguint_range(&low, &high))
+        .find(|base| !miller_rabin_single(testee, base.clone()))
+        .map(Witness::MillerRabin)
+}
+
+/// `lambda(n) = lcm(p_1 - 1, ..., p_k - 1)` for a square-free `n` with the given factors.
+pub fn carmichael_lambda<'a>(primes: impl IntoIterator<Item = &'a BigUint>) -> BigUint {
+    primes
+        .into_iter()
+        .fold(BigUint::one(), |lambda, prime| lambda.lcm(&(prime - 1_u32)))
+}
+
+/// `a^-1 mod modulus`, or `None` when `a` and `modulus` are not coprime.
+pub fn mod_inverse(a: &BigUint, modulus: &BigUint) -> Option<BigUint> {
+    let modulus = modulus.to_bigint().unwrap();
+    let result = BigInt::extended_gcd(&a.to_bigint().unwrap(), &modulus);
+    if result.gcd.is_one() {
+        result.x.mod_floor(&modulus).to_biguint()
     } else {
-        return_val
+        None
     }
 }
 
@@ -371,3 +1154,703 @@ pub fn exgcd(first: &BigUint, second: &BigUint) -> (BigInt, BigInt, BigInt) {
         ((pair.0).1, (pair.0).0, (pair.0).2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::VarTime;
+    use crate::convert::{base64_to_private_key, private_key_to_base64};
+    use crate::key::CrtParams;
+
+    #[test]
+    fn primes_are_drawn_independently() {
+        let mut utils = PrimeUtils::new(1024);
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let mut primes: Vec<BigUint> = vec![];
+        for _ in 0..4 {
+            let (_, key) = utils.gen_key(&e).unwrap();
+            let crt = key.crt.unwrap();
+            assert!(primes_far_apart(&crt.p, &crt.q, 512));
+            assert!(!primes.contains(&crt.p) && !primes.contains(&crt.q));
+            primes.extend([crt.p, crt.q]);
+        }
+    }
+
+    /// Rewinds to the start of its stream once, on the first draw at or past `rewind_at`.
+    struct Rewinding {
+        rng: ChaCha20Rng,
+        rewind_at: u128,
+        rewound: bool,
+    }
+
+    impl Rewinding {
+        fn before_draw(&mut self) -> &mut ChaCha20Rng {
+            if !self.rewound && self.rng.get_word_pos() >= self.rewind_at {
+                self.rng.set_word_pos(0);
+                self.rewound = true;
+            }
+            &mut self.rng
+        }
+    }
+
+    impl RngCore for Rewinding {
+        fn next_u32(&mut self) -> u32 {
+            self.before_draw().next_u32()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.before_draw().next_u64()
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            self.before_draw().fill_bytes(dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.before_draw().try_fill_bytes(dest)
+        }
+    }
+
+    impl CryptoRng for Rewinding {}
+
+    #[test]
+    fn primes_too_close_together_are_drawn_again() {
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        // Where the stream stands once the first prime has been drawn.
+        let mut first = PrimeUtils::with_rng(1024, ChaCha20Rng::seed_from_u64(35));
+        let p = first.gen_prime_coprime_to(512, &e);
+        let rng = Rewinding {
+            rng: ChaCha20Rng::seed_from_u64(35),
+            rewind_at: first.rng.get_word_pos(),
+            rewound: false,
+        };
+
+        // The second search replays the first and finds p again, which must be rejected.
+        let mut utils = PrimeUtils::with_rng(1024, rng);
+        let (_, key) = utils.gen_key(&e).unwrap();
+        assert!(utils.rng.rewound);
+        let crt = key.crt.unwrap();
+        assert_eq!(crt.p, p);
+        assert_ne!(crt.q, p);
+        assert!(primes_far_apart(&crt.p, &crt.q, 512));
+    }
+
+    #[test]
+    fn small_exponent_keys_round_trip() {
+        let mut utils = PrimeUtils::new(512);
+        for e in [3_u32, 17, 257] {
+            let (public_key, private_key) = utils.gen_key(&BigUint::from(e)).unwrap();
+            let message = BigUint::from(0xdead_beef_u32);
+            let secret = encrypt_uint(&public_key, &message);
+            assert_eq!(decrypt_uint(&private_key, &secret).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn crt_decryption_matches_plain_modpow() {
+        let mut utils = PrimeUtils::new(512);
+        let (public_key, private_key) = utils.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+        let legacy_key = RSAPrivateKey::new(private_key.n.clone(), private_key.d.clone());
+        for message in [0_u32, 1, 2, 0xdead_beef] {
+            let message = BigUint::from(message);
+            let secret = encrypt_uint(&public_key, &message);
+            assert_eq!(decrypt_uint(&private_key, &secret).unwrap(), message);
+            assert_eq!(decrypt_uint(&legacy_key, &secret).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn keys_edited_after_use_drop_their_contexts() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(38));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (public_key, mut key) = utils.gen_key(&e).unwrap();
+        let message = BigUint::from(0xdead_beef_u32);
+        let secret = encrypt_uint(&public_key, &message);
+        assert_eq!(decrypt_uint(&key, &secret).unwrap(), message);
+
+        // Without e there is no fault check to catch contexts built for p and q.
+        key.crt = None;
+        key.e = None;
+        assert_eq!(decrypt_uint(&key, &secret).unwrap(), message);
+
+        let (other_public_key, other_key) = utils.gen_key(&e).unwrap();
+        key.n = other_key.n.clone();
+        key.d = other_key.d.clone();
+        let secret = encrypt_uint(&other_public_key, &message);
+        assert_eq!(decrypt_uint(&key, &secret).unwrap(), message);
+    }
+
+    #[test]
+    fn multi_prime_keys_round_trip() {
+        for prime_count in [3, 4] {
+            let mut utils = PrimeUtils::new(768);
+            let (public_key, private_key) = utils
+                .gen_multi_prime_key(&BigUint::from(DEFAULT_EXPONENT), prime_count)
+                .unwrap();
+            assert_eq!(public_key.0.bits(), 768);
+            let crt = private_key.crt.as_ref().unwrap();
+            assert_eq!(crt.others.len(), prime_count - 2);
+            let legacy_key = RSAPrivateKey::new(private_key.n.clone(), private_key.d.clone());
+            let message = BigUint::from(0xdead_beef_u32);
+            let secret = encrypt_uint(&public_key, &message);
+            assert_eq!(decrypt_uint(&private_key, &secret).unwrap(), message);
+            assert_eq!(decrypt_uint(&legacy_key, &secret).unwrap(), message);
+
+            let encoded = private_key_to_base64(&private_key);
+            assert_eq!(base64_to_private_key(&encoded).unwrap(), private_key);
+        }
+        for malformed in ["AQ-AQ-AQ-AQ", "@@-AQ", "AQ--AQ", ""] {
+            assert_eq!(
+                base64_to_private_key(malformed),
+                Err(RsaError::MalformedKey)
+            );
+        }
+        assert_eq!(
+            PrimeUtils::new(512)
+                .gen_multi_prime_key(&BigUint::from(DEFAULT_EXPONENT), 9)
+                .map(|_| ()),
+            Err(RsaError::InvalidPrimeCount)
+        );
+    }
+
+    #[test]
+    fn parallel_search_matches_sequential_guarantees() {
+        fn assert_send<T: Send>() {}
+        assert_send::<PrimeUtils>();
+
+        let mut utils = PrimeUtils::new(512).with_threads(4);
+        for _ in 0..4 {
+            let prime = utils.gen_prime(256);
+            assert_eq!(prime.bits(), 256);
+            assert!(prime.bit(254));
+            assert!(check_prime(&mut utils.rng, &prime));
+        }
+        let ((n, _), _) = utils.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+        assert_eq!(n.bits(), 512);
+    }
+
+    #[test]
+    fn primes_of_every_small_size_are_found() {
+        let mut utils = PrimeUtils::with_rng(64, ChaCha20Rng::seed_from_u64(37));
+        for bit_size in 2..=20 {
+            let prime = utils.gen_prime(bit_size);
+            assert_eq!(prime.bits(), bit_size);
+            assert!(prime.bit(bit_size - 2));
+            assert!(is_prime_u64(prime.to_u64().unwrap()));
+        }
+        for bit_size in 3..=20 {
+            let prime = utils.gen_safe_prime(bit_size).to_u64().unwrap();
+            assert_eq!(64 - prime.leading_zeros(), bit_size as u32);
+            assert!(is_prime_u64(prime) && is_prime_u64(prime / 2));
+        }
+    }
+
+    #[test]
+    fn sieve_skips_exactly_the_small_multiples() {
+        let start = BigUint::from(1_000_001_u32);
+        let sieved: Vec<BigUint> = Sieve::new(start.clone(), BigUint::from(2_u32), false)
+            .take(64)
+            .collect();
+        let divided: Vec<BigUint> = (0..SEARCH_RANGE)
+            .step_by(2)
+            .map(|offset| &start + offset)
+            .filter(trial_division)
+            .take(64)
+            .collect();
+        assert_eq!(sieved, divided);
+
+        let sieved: Vec<BigUint> = Sieve::new(start.clone(), BigUint::from(2_u32), true)
+            .take(16)
+            .collect();
+        let divided: Vec<BigUint> = (0..SEARCH_RANGE)
+            .step_by(2)
+            .map(|offset| &start + offset)
+            .filter(|q| trial_division(q) && trial_division(&((q << 1) + 1_u32)))
+            .take(16)
+            .collect();
+        assert_eq!(sieved, divided);
+
+        let step = BigUint::from(2_u32 * 17863 * 65537);
+        let sieved: Vec<BigUint> = Sieve::new(start.clone(), step.clone(), false).collect();
+        let divided: Vec<BigUint> = (0..SEARCH_RANGE / 2)
+            .map(|index| &start + index * &step)
+            .filter(trial_division)
+            .collect();
+        assert_eq!(sieved, divided);
+    }
+
+    #[test]
+    fn baillie_psw_rejects_pseudoprimes() {
+        // Carmichael numbers pass the Fermat test for every coprime base.
+        for n in [561_u64, 1105, 1729, 2465, 2821, 6601, 8911, 41041, 825265] {
+            let n = BigUint::from(n);
+            assert!(fermat(&n, &BigUint::from(2_u32)));
+            assert!(!baillie_psw(&n));
+        }
+        // Strong pseudoprimes to base 2.
+        for n in [2047_u64, 3277, 4033, 4681, 8321, 15841, 29341, 42799, 49141] {
+            let n = BigUint::from(n);
+            assert!(miller_rabin_single(&n, BigUint::from(2_u32)));
+            assert!(!baillie_psw(&n));
+        }
+        // Strong pseudoprimes to every prime base up to 7 and up to 37 respectively.
+        for (n, bases) in [(3215031751_u128, 4), (318665857834031151167461, 12)] {
+            let n = BigUint::from(n);
+            for &base in &SMALL_PRIMES[..bases] {
+                assert!(miller_rabin_single(&n, BigUint::from(base)));
+            }
+            assert!(!baillie_psw(&n));
+        }
+        // Strong Lucas pseudoprimes are caught by the base-2 half.
+        for n in [5459_u32, 5777, 10877, 16109, 18971] {
+            let n = BigUint::from(n);
+            assert!(strong_lucas_probable_prime(&n));
+            assert!(!baillie_psw(&n));
+        }
+    }
+
+    #[test]
+    fn baillie_psw_accepts_primes() {
+        for &prime in SMALL_PRIMES.iter() {
+            assert!(baillie_psw(&BigUint::from(prime)));
+        }
+        for exponent in [61, 89, 107, 127, 521] {
+            let mersenne = (BigUint::one() << exponent) - 1_u32;
+            assert!(baillie_psw(&mersenne));
+            assert!(!baillie_psw(&(mersenne + 2_u32)));
+        }
+        let mut utils = PrimeUtils::new(512).with_primality_test(PrimalityTest::BailliePsw);
+        for _ in 0..4 {
+            let prime = utils.gen_prime(256);
+            assert!(is_prime(&prime, PrimalityTest::MillerRabin));
+        }
+        assert!(!is_prime(
+            &BigUint::from(3215031751_u64),
+            PrimalityTest::BailliePsw
+        ));
+    }
+
+    #[test]
+    fn provable_keys_come_with_certificates() {
+        let mut utils = PrimeUtils::new(512).with_prime_kind(PrimeKind::Provable);
+        let (_, private_key) = utils.gen_key(&BigUint::from(3_u32)).unwrap();
+        let crt = private_key.crt.unwrap();
+        let proven: Vec<BigUint> = utils
+            .certificates()
+            .iter()
+            .filter(|certificate| certificate.verify())
+            .map(PrimeCertificate::prime)
+            .collect();
+        assert_eq!(proven, vec![crt.p, crt.q]);
+    }
+
+    #[test]
+    fn u64_primality_is_exact() {
+        let mut composite = vec![false; 100_000];
+        for i in 2..composite.len() {
+            if !composite[i] {
+                (i * i..composite.len())
+                    .step_by(i)
+                    .for_each(|j| composite[j] = true);
+            }
+            assert_eq!(is_prime_u64(i as u64), !composite[i], "{}", i);
+        }
+        assert!(!is_prime_u64(0) && !is_prime_u64(1));
+        assert!(is_prime_u64(u64::MAX - 58));
+        assert!(is_prime_u64((1 << 61) - 1));
+        assert!(!is_prime_u64(3825123056546413051));
+        assert!(!is_prime_u64(4294967297));
+    }
+
+    #[test]
+    fn probable_prime_handles_every_size() {
+        for n in 0_u32..64 {
+            let n = BigUint::from(n);
+            assert_eq!(is_probable_prime(&n, 1), is_prime_u64(n.to_u64().unwrap()));
+        }
+        let mersenne = (BigUint::one() << 127) - 1_u32;
+        assert!(is_probable_prime(&mersenne, miller_rabin_rounds(127)));
+        assert!(!is_probable_prime(&(&mersenne * &mersenne), 1));
+        assert!(!is_probable_prime(
+            &BigUint::from(318665857834031151167461_u128),
+            miller_rabin_rounds(79)
+        ));
+        assert_eq!(miller_rabin_rounds(1024), 5);
+    }
+
+    #[test]
+    fn witnesses_show_compositeness() {
+        assert_eq!(find_witness(&BigUint::from(2_u32), 1), None);
+        assert_eq!(find_witness(&BigUint::from(17863_u32), 1), None);
+        assert_eq!(
+            find_witness(&BigUint::from(17863_u32 * 3), 1),
+            Some(Witness::Factor(3))
+        );
+        // Strong pseudoprime to every prime base up to 31.
+        assert_eq!(
+            find_witness(&BigUint::from(3825123056546413051_u64), 1),
+            Some(Witness::MillerRabin(BigUint::from(37_u32)))
+        );
+        let mersenne = (BigUint::one() << 127) - 1_u32;
+        assert_eq!(find_witness(&mersenne, 10), None);
+        let base = match find_witness(&(&mersenne * &mersenne), 1) {
+            Some(Witness::MillerRabin(base)) => base,
+            other => panic!("expected a Miller-Rabin witness, got {:?}", other),
+        };
+        assert!(!miller_rabin_single(&(&mersenne * &mersenne), base));
+    }
+
+    #[test]
+    fn safe_primes_have_prime_halves() {
+        for sieve in [true, false] {
+            let mut utils =
+                PrimeUtils::with_rng(256, ChaCha20Rng::seed_from_u64(13)).with_sieve(sieve);
+            let p = utils.gen_safe_prime(128);
+            assert_eq!(p.bits(), 128);
+            assert!(p.bit(126));
+            assert!(is_prime(&p, PrimalityTest::BailliePsw));
+            assert!(is_prime(&(p >> 1), PrimalityTest::BailliePsw));
+        }
+    }
+
+    #[test]
+    fn strong_primes_have_large_factors_around_them() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(14));
+        for bit_size in [64, 256] {
+            let (p, r, s) = utils.gen_gordon_prime(bit_size);
+            assert_eq!(p.bits(), bit_size);
+            assert!(p.bit(bit_size - 2));
+            assert!(is_prime(&p, PrimalityTest::BailliePsw));
+            assert!(is_prime(&r, PrimalityTest::BailliePsw));
+            assert!(is_prime(&s, PrimalityTest::BailliePsw));
+            assert!((&p - 1_u32).is_multiple_of(&r));
+            assert!((&p + 1_u32).is_multiple_of(&s));
+            assert!(r.bits() >= bit_size * 3 / 8 && s.bits() >= bit_size * 3 / 8);
+        }
+    }
+
+    #[test]
+    fn safe_and_strong_keys_round_trip() {
+        for kind in [PrimeKind::Safe, PrimeKind::Strong] {
+            let mut utils =
+                PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(15)).with_prime_kind(kind);
+            let (public_key, private_key) =
+                utils.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+            assert_eq!(public_key.0.bits(), 512);
+            let message = "safe and strong";
+            assert_eq!(
+                decrypt(&private_key, &encrypt(&public_key, message).unwrap()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn validation_names_the_broken_part() {
+        let mut utils = PrimeUtils::with_rng(384, ChaCha20Rng::seed_from_u64(16));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (_, key) = utils.gen_multi_prime_key(&e, 3).unwrap();
+        assert_eq!(key.validate(), Ok(()));
+
+        let bare = RSAPrivateKey {
+            crt: None,
+            ..key.clone()
+        };
+        assert_eq!(bare.validate(), Ok(()));
+        let legacy = RSAPrivateKey::new(key.n.clone(), key.d.clone());
+        assert_eq!(legacy.validate(), Err(RsaError::MissingPublicExponent));
+
+        let broken = |edit: &dyn Fn(&mut RSAPrivateKey)| {
+            let mut broken = key.clone();
+            edit(&mut broken);
+            broken.validate().unwrap_err()
+        };
+        fn crt(key: &mut RSAPrivateKey) -> &mut CrtParams {
+            key.crt.as_mut().unwrap()
+        }
+        assert_eq!(broken(&|key| key.n += 2_u32), RsaError::FactorMismatch);
+        assert_eq!(
+            broken(&|key| crt(key).q = BigUint::one()),
+            RsaError::FactorMismatch
+        );
+        assert_eq!(
+            broken(&|key| key.d += 2_u32),
+            RsaError::InvalidPrivateExponent
+        );
+        assert_eq!(
+            broken(&|key| key.e = Some(BigUint::from(4_u32))),
+            RsaError::EvenExponent
+        );
+        assert_eq!(
+            broken(&|key| crt(key).dq += 1_u32),
+            RsaError::CrtExponentMismatch
+        );
+        assert_eq!(
+            broken(&|key| crt(key).others[0].d += 1_u32),
+            RsaError::CrtExponentMismatch
+        );
+        assert_eq!(
+            broken(&|key| crt(key).qinv += 1_u32),
+            RsaError::CrtCoefficientMismatch
+        );
+        assert_eq!(
+            broken(&|key| crt(key).others[0].t += 1_u32),
+            RsaError::CrtCoefficientMismatch
+        );
+        // Without factors, only the round trips can catch a wrong d.
+        assert_eq!(
+            broken(&|key| {
+                key.crt = None;
+                key.d += 2_u32;
+            }),
+            RsaError::DecryptionMismatch
+        );
+    }
+
+    #[test]
+    fn private_exponent_follows_the_totient() {
+        let primes = [BigUint::from(61_u32), BigUint::from(53_u32)];
+        let e = BigUint::from(17_u32);
+        assert_eq!(Totient::Euler.of(&primes), BigUint::from(3120_u32));
+        assert_eq!(Totient::Carmichael.of(&primes), BigUint::from(780_u32));
+        let d = |totient: Totient| mod_inverse(&e, &totient.of(&primes)).unwrap();
+        assert_eq!(d(Totient::Euler), BigUint::from(2753_u32));
+        assert_eq!(d(Totient::Carmichael), BigUint::from(413_u32));
+
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let mut exponents = vec![];
+        for totient in [Totient::Carmichael, Totient::Euler] {
+            let mut utils =
+                PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(26)).with_totient(totient);
+            let (public_key, private_key) = utils.gen_multi_prime_key(&e, 3).unwrap();
+            assert_eq!(private_key.validate(), Ok(()));
+            let message = "either totient works";
+            assert_eq!(
+                decrypt(&private_key, &encrypt(&public_key, message).unwrap()).unwrap(),
+                message
+            );
+            let primes: Vec<BigUint> = private_key.crt.unwrap().primes().cloned().collect();
+            assert_eq!(
+                private_key.d,
+                mod_inverse(&e, &totient.of(&primes)).unwrap()
+            );
+            exponents.push(private_key.d);
+        }
+        // Same seed, same primes: only d differs, and lambda's is the smaller one.
+        assert!(exponents[0] < exponents[1]);
+    }
+
+    #[test]
+    fn blinding_leaves_results_unchanged() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(27));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (_, key) = utils.gen_multi_prime_key(&e, 3).unwrap();
+        let secrets: Vec<BigUint> = (0..8)
+            .map(|_| utils.rng.gen_biguint_below(&key.n))
+            .collect();
+        let expected: Vec<BigUint> = secrets.iter().map(|s| s.modpow(&key.d, &key.n)).collect();
+        let bare = RSAPrivateKey {
+            crt: None,
+            ..key.clone()
+        };
+        let legacy = RSAPrivateKey::new(key.n.clone(), key.d.clone());
+        for exponent_blinding in [false, true] {
+            for key in [&key, &bare, &legacy] {
+                let key = key
+                    .clone()
+                    .with_blinding_rng(ChaCha20Rng::seed_from_u64(28))
+                    .with_exponent_blinding(exponent_blinding);
+                for (secret, message) in secrets.iter().zip(&expected) {
+                    assert_eq!(decrypt_uint(&key, secret).unwrap(), *message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn blinding_factors_are_refreshed_by_squaring() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(29));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (_, key) = utils.gen_key(&e).unwrap();
+        let key = key.with_blinding_rng(ChaCha20Rng::seed_from_u64(30));
+        let n = &key.n;
+        assert_eq!(key.blinding.cached_factors(), None);
+        decrypt_uint(&key, &BigUint::from(2_u32)).unwrap();
+        let (blind, unblind) = key.blinding.cached_factors().unwrap();
+        let r = mod_inverse(&unblind, n).unwrap();
+        assert_eq!(r.modpow(&e, n), blind);
+        decrypt_uint(&key, &BigUint::from(3_u32)).unwrap();
+        let next = key.blinding.cached_factors().unwrap();
+        assert_eq!(next, (&blind * &blind % n, &unblind * &unblind % n));
+
+        // The same injected generator draws the same r; a clone draws its own.
+        let twin = key
+            .clone()
+            .with_blinding_rng(ChaCha20Rng::seed_from_u64(30));
+        decrypt_uint(&twin, &BigUint::from(2_u32)).unwrap();
+        assert_eq!(twin.blinding.cached_factors().unwrap(), (blind, unblind));
+        let clone = key.clone();
+        decrypt_uint(&clone, &BigUint::from(2_u32)).unwrap();
+        assert_ne!(
+            clone.blinding.cached_factors(),
+            key.blinding.cached_factors()
+        );
+    }
+
+    #[test]
+    fn keys_edited_after_use_draw_new_blinding_factors() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(39));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (_, mut key) = utils.gen_key(&e).unwrap();
+        decrypt_uint(&key, &BigUint::from(2_u32)).unwrap();
+
+        let (other_public_key, other_key) = utils.gen_key(&e).unwrap();
+        key.n = other_key.n.clone();
+        key.d = other_key.d.clone();
+        key.crt = other_key.crt.clone();
+        let message = BigUint::from(0xdead_beef_u32);
+        let secret = encrypt_uint(&other_public_key, &message);
+        assert_eq!(decrypt_uint(&key, &secret), Ok(message));
+    }
+
+    #[test]
+    fn faulty_crt_halves_are_withheld() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(31));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (_, key) = utils.gen_key(&e).unwrap();
+        let crt = key.crt.clone().unwrap();
+        let secret = utils.rng.gen_biguint_below(&key.n);
+        let p = ConstantTime::from_bytes_be(&crt.p.to_bytes_be());
+        let one = ConstantTime::from_bytes_be(&[1]);
+        let glitch = |m1: Fixed| ConstantTime::sub_mod(&m1, &one, &p);
+
+        // Unchecked, the faulty result is right modulo q only, and gives q away.
+        let contexts = key.contexts();
+        let faulty =
+            private_exponentiation::<ConstantTime, _>(&key, &contexts.private, &secret, glitch);
+        let faulty = faulty.unwrap();
+        let error = (faulty.modpow(&e, &key.n) + &key.n - &secret) % &key.n;
+        assert_eq!(error.gcd(&key.n), crt.q);
+
+        assert_eq!(
+            decrypt_uint_with_fault(&key, &secret, glitch),
+            Err(RsaError::FaultDetected)
+        );
+        let key = key.with_exponent_blinding(true);
+        assert_eq!(
+            decrypt_uint_with_fault(&key, &secret, glitch),
+            Err(RsaError::FaultDetected)
+        );
+        assert_eq!(
+            decrypt_uint(&key, &secret),
+            Ok(secret.modpow(&key.d, &key.n))
+        );
+    }
+
+    #[test]
+    fn private_operations_agree_across_backends() {
+        let mut utils = PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(34));
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        let (_, key) = utils.gen_multi_prime_key(&e, 3).unwrap();
+        let bare = RSAPrivateKey {
+            crt: None,
+            ..key.clone()
+        };
+        let legacy = RSAPrivateKey::new(key.n.clone(), key.d.clone());
+        for exponent_blinding in [false, true] {
+            for key in [&key, &bare, &legacy] {
+                let key = key.clone().with_exponent_blinding(exponent_blinding);
+                let var_time: Vec<_> = match &key.crt {
+                    Some(crt) => crt.primes().map(VarTime::modulus).collect(),
+                    None => vec![VarTime::modulus(&key.n)],
+                };
+                let contexts = key.contexts();
+                let constant_time = &contexts.private;
+                for _ in 0..4 {
+                    let secret = utils.rng.gen_biguint_below(&key.n);
+                    let expected = secret.modpow(&key.d, &key.n);
+                    let results = [
+                        private_exponentiation::<VarTime, _>(&key, &var_time, &secret, |m1| m1),
+                        private_exponentiation::<ConstantTime, _>(
+                            &key,
+                            constant_time,
+                            &secret,
+                            |m1| m1,
+                        ),
+                    ];
+                    assert_eq!(results, [Ok(expected.clone()), Ok(expected)]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn seeded_rng_reproduces_keys() {
+        let gen = |seed| {
+            PrimeUtils::with_rng(512, ChaCha20Rng::seed_from_u64(seed))
+                .gen_key(&BigUint::from(DEFAULT_EXPONENT))
+                .unwrap()
+        };
+        assert_eq!(gen(7), gen(7));
+        assert_ne!(gen(7).0, gen(8).0);
+    }
+
+    #[test]
+    fn miller_rabin_accepts_minus_one_before_squaring() {
+        // d is odd, so (p - 1)^d = -1 mod p without any squaring.
+        for prime in [5_u32, 7, 13, 17863] {
+            let prime = BigUint::from(prime);
+            assert!(miller_rabin_single(&prime, &prime - 1_u32));
+        }
+    }
+
+    #[test]
+    fn invalid_exponents_are_rejected() {
+        let mut utils = PrimeUtils::new(512);
+        let mut gen = |e: BigUint| utils.gen_key(&e).map(|_| ());
+        assert_eq!(gen(BigUint::from(65536_u32)), Err(RsaError::EvenExponent));
+        assert_eq!(gen(BigUint::from(1_u32)), Err(RsaError::ExponentTooSmall));
+        assert_eq!(
+            gen((BigUint::one() << 511) + 1_u32),
+            Err(RsaError::ExponentTooLarge)
+        );
+    }
+
+    #[test]
+    fn moduli_without_room_for_a_block_are_rejected() {
+        let e = BigUint::from(DEFAULT_EXPONENT);
+        for bytes in [1, 11] {
+            let n = (BigUint::one() << (8 * bytes - 1)) + 1_u32;
+            assert_eq!(
+                encrypt(&(n, e.clone()), "a"),
+                Err(RsaError::NoRoomForMessage)
+            );
+        }
+        let n = (BigUint::one() << 95) + 1_u32;
+        assert!(encrypt(&(n, e), "a").is_ok());
+    }
+
+    #[test]
+    fn degenerate_private_moduli_are_rejected() {
+        let secret = BigUint::from(2_u32);
+        for (key, error) in [
+            ("AA-Aw-AQ", RsaError::InvalidModulus),
+            ("AQ-Aw-AQ", RsaError::InvalidModulus),
+            ("DA-Aw-AQ", RsaError::UnsupportedModulus),
+        ] {
+            let key = base64_to_private_key(key).unwrap();
+            assert_eq!(decrypt_uint(&key, &secret), Err(error), "n = {}", key.n);
+        }
+    }
+
+    #[test]
+    fn modulus_has_exact_bit_length() {
+        for key_size in [256, 257, 384, 511] {
+            let mut utils = PrimeUtils::new(key_size);
+            for _ in 0..16 {
+                let ((n, _), _) = utils.gen_key(&BigUint::from(DEFAULT_EXPONENT)).unwrap();
+                assert_eq!(n.bits(), key_size);
+            }
+        }
+    }
+}